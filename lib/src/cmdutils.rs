@@ -0,0 +1,5731 @@
+//! Helpers intended for [`std::process::Command`] and related structures.
+//!
+//! Errors returned from these helpers intentionally do *not* include the
+//! command string; it may be verbose, so we leave it to the caller to add
+//! that if they want via e.g. [`anyhow::Context`]. For debugging, this can
+//! be changed via [`set_verbose_errors`].
+//!
+//! When dry-run mode is enabled for the current thread via [`set_dry_run`],
+//! the [`CommandRunExt`] helpers which only report whether the child succeeded
+//! (such as [`CommandRunExt::run`]) log the command they would have executed
+//! and return success. The helpers which return the child's output instead
+//! fail in dry-run mode, and [`AsyncCommandRunExt`] ignores it.
+//!
+//! So that a child writing excessively to stderr cannot fill e.g. a small `/tmp`, the
+//! [`CommandRunExt`] helpers which capture stderr to a file, but only include its
+//! trailing part in errors, discard the rest once more than 16 MiB has been captured.
+//!
+//! As a safety net against hung children, if the `BOOTC_SUBPROC_TIMEOUT` environment
+//! variable is set to a number of seconds, all [`CommandRunExt`] helpers other than
+//! [`CommandRunExt::run_interactive`] and [`CommandRunExt::spawn_capturing`] (and
+//! [`CommandRunExt::run_with_timeout`], which overrides it) apply it as a timeout.
+//! [`CommandPipeline`] and [`AsyncCommandRunExt`] have their own deadlines instead.
+
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::File;
+use std::future::Future;
+use std::io::{BufRead, Read, Seek, Write};
+use std::os::fd::RawFd;
+use std::os::unix::process::{CommandExt, ExitStatusExt as _};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use rustix::process::{Pid, Resource, Signal};
+use tokio_util::sync::CancellationToken;
+
+/// The namespaces which may be passed to [`CommandRunExt::run_in_namespace`].
+pub(crate) type NamespaceFlags = rustix::thread::UnshareFlags;
+
+/// How long we wait after sending `SIGTERM` to a timed out child
+/// before escalating to `SIGKILL`.
+const TIMEOUT_GRACE_PERIOD: Duration = Duration::from_secs(2);
+
+thread_local! {
+    static DRY_RUN: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Enable or disable dry-run mode for the current thread.
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) fn set_dry_run(enabled: bool) {
+    DRY_RUN.set(enabled);
+}
+
+/// If dry-run mode is enabled, log the command and return `true`.
+fn dry_run_skip(cmd: &Command) -> bool {
+    let dry_run = DRY_RUN.get();
+    if dry_run {
+        tracing::info!("Would execute: {}", redacted_cmdline(cmd, &[]));
+    }
+    dry_run
+}
+
+/// Return an error if dry-run mode is enabled; used by helpers which need the child's output.
+fn ensure_not_dry_run() -> Result<()> {
+    if DRY_RUN.get() {
+        anyhow::bail!("Cannot capture output in dry-run mode");
+    }
+    Ok(())
+}
+
+/// Whether errors include the command line; see [`set_verbose_errors`].
+static VERBOSE_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Enable or disable including the (redacted) command line in the errors returned
+/// when a child exits unsuccessfully. This is also enabled if the `BOOTC_CMD_VERBOSE`
+/// environment variable is set to `1`.
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) fn set_verbose_errors(enabled: bool) {
+    VERBOSE_ERRORS.store(enabled, Ordering::Relaxed);
+}
+
+fn verbose_errors() -> bool {
+    static FROM_ENV: OnceLock<bool> = OnceLock::new();
+    VERBOSE_ERRORS.load(Ordering::Relaxed)
+        || *FROM_ENV.get_or_init(|| std::env::var_os("BOOTC_CMD_VERBOSE").is_some_and(|v| v == "1"))
+}
+
+/// If the error is because the child was killed (e.g. by a signal, or because it timed
+/// out), clarify that its output is likely incomplete, rather than e.g. leaving the
+/// caller to wonder why it is not valid JSON.
+fn annotate_terminated(e: anyhow::Error) -> anyhow::Error {
+    let terminated = e.is::<CommandTimedOut>()
+        || e.downcast_ref::<CommandError>()
+            .is_some_and(|e| e.status.signal().is_some());
+    if terminated {
+        e.context("Subprocess was terminated before completing output")
+    } else {
+        e
+    }
+}
+
+/// Parse the value of `BOOTC_SUBPROC_TIMEOUT`, in (possibly fractional) seconds.
+fn parse_default_timeout(v: &std::ffi::OsStr) -> Option<Duration> {
+    let r = v
+        .to_str()
+        .and_then(|v| v.trim().parse::<f64>().ok())
+        .and_then(|v| Duration::try_from_secs_f64(v).ok())
+        .filter(|v| !v.is_zero());
+    if r.is_none() {
+        tracing::warn!("Ignoring invalid BOOTC_SUBPROC_TIMEOUT: {v:?}");
+    }
+    r
+}
+
+/// The timeout applied by the [`CommandRunExt`] helpers if none is specified; see
+/// the module documentation.
+fn default_timeout() -> Option<Duration> {
+    static FROM_ENV: OnceLock<Option<Duration>> = OnceLock::new();
+    *FROM_ENV.get_or_init(|| {
+        std::env::var_os("BOOTC_SUBPROC_TIMEOUT")
+            .as_deref()
+            .and_then(parse_default_timeout)
+    })
+}
+
+/// If set, the directory in which tempfiles capturing the output of children are created.
+static CAPTURE_TEMPDIR: RwLock<Option<PathBuf>> = RwLock::new(None);
+
+/// Set the directory in which the tempfiles capturing output of children are created,
+/// for use when the default temporary directory is e.g. read-only or small.
+/// If `None`, an anonymous in-memory file is used where supported, and otherwise
+/// a tempfile in the default from [`std::env::temp_dir`].
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) fn set_capture_tempdir(dir: Option<&Path>) {
+    let mut v = CAPTURE_TEMPDIR.write().unwrap_or_else(|e| e.into_inner());
+    *v = dir.map(ToOwned::to_owned);
+}
+
+/// Create an anonymous tempfile for capturing the output of a child. Unless a
+/// directory was set via [`set_capture_tempdir`], this is a memfd, so that we
+/// don't depend on a writable filesystem (e.g. a small `/tmp` on a read-only root).
+fn capture_tempfile() -> Result<File> {
+    let dir = CAPTURE_TEMPDIR.read().unwrap_or_else(|e| e.into_inner());
+    if let Some(dir) = dir.as_deref() {
+        return tempfile::tempfile_in(dir)
+            .with_context(|| format!("Creating tempfile in {}", dir.display()));
+    }
+    match rustix::fs::memfd_create("bootc-capture", rustix::fs::MemfdFlags::CLOEXEC) {
+        Ok(fd) => Ok(fd.into()),
+        Err(e) => {
+            tracing::debug!("memfd_create failed, falling back to tempfile: {e}");
+            tempfile::tempfile().context("Creating tempfile")
+        }
+    }
+}
+
+/// Return a sink for output which should be discarded. This is `/dev/null` if possible,
+/// but as we may run before `/dev` is fully populated (or on a broken root), we fall
+/// back to an anonymous tempfile (see [`capture_tempfile`]), which is discarded when
+/// the last reference to it is closed.
+fn null_sink() -> Result<Stdio> {
+    let e = match File::options().write(true).open("/dev/null") {
+        Ok(f) => return Ok(f.into()),
+        Err(e) => e,
+    };
+    tracing::debug!("Failed to open /dev/null, falling back to tempfile: {e}");
+    Ok(capture_tempfile()?.into())
+}
+
+/// The PIDs of children currently executing via [`CommandRunExt::run`] (and the
+/// helpers built on it) or the [`AsyncCommandRunExt`] helpers.
+static RUNNING_CHILDREN: Mutex<BTreeSet<i32>> = Mutex::new(BTreeSet::new());
+
+/// Registers a child in [`RUNNING_CHILDREN`], until dropped.
+#[derive(Debug)]
+struct ChildRegistration(i32);
+
+impl ChildRegistration {
+    fn new(pid: u32) -> Self {
+        let pid = pid as i32;
+        let mut children = RUNNING_CHILDREN.lock().unwrap_or_else(|e| e.into_inner());
+        children.insert(pid);
+        Self(pid)
+    }
+}
+
+impl Drop for ChildRegistration {
+    fn drop(&mut self) {
+        let mut children = RUNNING_CHILDREN.lock().unwrap_or_else(|e| e.into_inner());
+        children.remove(&self.0);
+    }
+}
+
+/// Send `SIGTERM` to all children currently executing via [`CommandRunExt::run`] (and
+/// the helpers built on it) or the [`AsyncCommandRunExt`] helpers, e.g. because we are
+/// being shut down. The callers will then return the resulting errors as usual.
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) fn shutdown_all() {
+    let children = RUNNING_CHILDREN.lock().unwrap_or_else(|e| e.into_inner());
+    for pid in children.iter().filter_map(|&pid| Pid::from_raw(pid)) {
+        if let Err(e) = rustix::process::kill_process(pid, Signal::Term) {
+            tracing::debug!("failed to send SIGTERM to {pid:?}: {e}");
+        }
+    }
+}
+
+/// Command line flags whose value is a secret, and hence must never be logged.
+const SECRET_FLAGS: &[&str] = &[
+    "--password",
+    "--passphrase",
+    "--creds",
+    "--src-creds",
+    "--dest-creds",
+    "--registry-token",
+];
+
+/// Replaces the value of secret arguments in [`redacted_cmdline`].
+const REDACTED: &str = "****";
+
+/// Return the program and arguments of the command as a string suitable for
+/// logging or error messages. The values of flags in [`SECRET_FLAGS`] or
+/// `extra`, whether given as `--flag value` or `--flag=value`, are replaced by `****`.
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) fn redacted_cmdline(cmd: &Command, extra: &[&str]) -> String {
+    let is_secret = |flag: &str| SECRET_FLAGS.contains(&flag) || extra.contains(&flag);
+    let mut r = vec![cmd.get_program().to_string_lossy().into_owned()];
+    let mut redact_next = false;
+    for arg in cmd.get_args() {
+        let arg = arg.to_string_lossy();
+        if std::mem::take(&mut redact_next) {
+            r.push(REDACTED.to_owned());
+            continue;
+        }
+        if let Some((flag, _)) = arg.split_once('=') {
+            if is_secret(flag) {
+                r.push(format!("{flag}={REDACTED}"));
+                continue;
+            }
+        }
+        redact_next = is_secret(&arg);
+        r.push(arg.into_owned());
+    }
+    r.join(" ")
+}
+
+/// Like [`parse_json_from_file`], but parse the content as YAML.
+fn parse_yaml_from_file<T: serde::de::DeserializeOwned>(mut f: &File, name: &str) -> Result<T> {
+    // An empty YAML stream is a valid null document, but we want to match the JSON behavior
+    let size = f.metadata().context("fstat")?.len();
+    anyhow::ensure!(
+        size > 0,
+        "Parsing YAML from {name}: unexpected empty output"
+    );
+    f.seek(std::io::SeekFrom::Start(0)).context("seek")?;
+    serde_yaml::from_reader(std::io::BufReader::new(f)).with_context(|| {
+        let snippet = first_utf8_content_from_file(f);
+        format!("Parsing YAML from {name}: {snippet}")
+    })
+}
+
+/// Construct a [`std::process::Command`] from a program and arguments, each of
+/// which may be any expression implementing `AsRef<OsStr>`; e.g.
+/// `cmd!("skopeo", "inspect", imgref)` or `cmd!(program, "--version")`.
+#[allow(unused_macros)]
+macro_rules! cmd {
+    ($program:expr $(, $arg:expr)* $(,)?) => {{
+        #[allow(unused_mut)]
+        let mut cmd = std::process::Command::new($program);
+        $(cmd.arg($arg);)*
+        cmd
+    }};
+}
+#[allow(unused_imports)]
+pub(crate) use cmd;
+
+/// Helpers intended for [`std::process::Command`].
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) trait CommandRunExt {
+    /// Synchronously execute the child, and return an error if the child exited unsuccessfully.
+    /// The child is killed if it exceeds the default timeout from `BOOTC_SUBPROC_TIMEOUT` (if set).
+    fn run(&mut self) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but the child's stdout is redirected to `/dev/null`
+    /// (or if that is unavailable, an anonymous tempfile) instead of being inherited,
+    /// so it can't interleave with our own output.
+    fn run_quiet(&mut self) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but retain up to `limit` trailing bytes of stderr
+    /// in the error (instead of the default 1024).
+    fn run_with_stderr_limit(&mut self, limit: usize) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but if the child has not exited within `timeout`
+    /// it is sent `SIGTERM`, and then `SIGKILL` after a short grace period.
+    /// In that case the returned error can be downcast to [`CommandTimedOut`].
+    fn run_with_timeout(&mut self, timeout: Duration) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but return an error if the child has not been
+    /// spawned within `budget`, e.g. because looking up the program blocks on a stuck
+    /// filesystem. The spawn happens on a separate thread; if it times out, the original
+    /// [`Command`] remains in use there, and is replaced by a copy with the same program,
+    /// arguments, environment variables and working directory (but not e.g. its stdio
+    /// configuration). Should the child be spawned later, it is killed. As such threads
+    /// may remain blocked, at most 8 may be pending before this fails immediately.
+    fn run_with_spawn_timeout(&mut self, budget: Duration) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but the child's stderr is copied to the provided
+    /// writer as it is written. The trailing content is still included in the error
+    /// if the child exits unsuccessfully.
+    fn run_with_stderr(&mut self, w: impl Write) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but stderr is captured via a pipe into a bounded
+    /// in-memory buffer instead of a tempfile, so the filesystem isn't touched at all.
+    /// Note that this waits for EOF on stderr, so unlike `run` it will block if the
+    /// child leaves behind a process which inherited stderr.
+    fn run_piped(&mut self) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but return the wall-clock time taken to
+    /// spawn the child and wait for it to exit.
+    fn run_timed(&mut self) -> Result<Duration>;
+
+    /// Like [`CommandRunExt::run`], but if the child fails, run it again up to a
+    /// total of `attempts` times, sleeping for `backoff` (doubled after each failure)
+    /// in between. The same [`Command`] is re-executed each time, so its program,
+    /// arguments, environment and working directory are preserved; stderr is
+    /// captured freshly for each attempt. The error from the final attempt is returned.
+    fn run_with_retries(&mut self, attempts: u32, backoff: Duration) -> Result<()>;
+
+    /// Like [`CommandRunExt::run_with_retries`], but each attempt is limited to
+    /// `timeout` (if provided) as with [`CommandRunExt::run_with_timeout`], and a
+    /// failed attempt is only retried if `should_retry` returns `true` for its error.
+    /// The predicate can distinguish timeouts by downcasting to [`CommandTimedOut`]
+    /// (which carries the exceeded duration), and unsuccessful exits by downcasting
+    /// to [`CommandError`] and checking [`CommandError::code`].
+    fn run_with_retries_if(
+        &mut self,
+        attempts: u32,
+        backoff: Duration,
+        timeout: Option<Duration>,
+        should_retry: impl Fn(&anyhow::Error) -> bool,
+    ) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but the child does not inherit our environment,
+    /// except for `PATH` and the variables named in `keep`. Variables explicitly
+    /// set on the [`Command`] are preserved.
+    fn run_clean_env(&mut self, keep: &[&str]) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but stdin, stdout and stderr are inherited, so
+    /// that the child can interact with the terminal (e.g. to prompt for a passphrase).
+    /// The tradeoff is that the error on failure cannot include any stderr.
+    fn run_interactive(&mut self) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but stdout and stderr are discarded (see
+    /// [`CommandRunExt::run_quiet`]) rather than captured, avoiding the overhead
+    /// for trivial commands whose output is irrelevant. As with
+    /// [`CommandRunExt::run_interactive`], the error on failure has no stderr.
+    fn run_discarding(&mut self) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but the child calls `setsid()` before executing,
+    /// so that it is in a new session and process group (whose ID is the PID of
+    /// the child), and hence does not receive e.g. signals sent to our process group.
+    /// Note this remains configured on the [`Command`] for later invocations.
+    fn run_in_new_session(&mut self) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but before executing, the child resets the
+    /// disposition of all signals to the default and unblocks them, for tools which
+    /// assume a default signal environment (e.g. that `SIGPIPE` terminates them)
+    /// rather than inheriting what was ignored or blocked in this process.
+    /// Note this remains configured on the [`Command`] for later invocations.
+    fn run_resetting_signals(&mut self) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but set the nice value of the child (clamped to
+    /// the valid range of -20 to 19) before executing, along with the corresponding
+    /// best-effort I/O priority (which would otherwise be inherited from this process
+    /// if set explicitly). If that is not permitted (e.g. raising priority without
+    /// `CAP_SYS_NICE`), the child is executed with its default priority, and a warning
+    /// is logged once it has exited.
+    /// Note this remains configured on the [`Command`] for later invocations.
+    fn run_with_priority(&mut self, nice: i32) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but apply the provided resource limits (as both the
+    /// soft and hard limit) to the child before executing. If the child is killed by a
+    /// signal, the error notes that it was resource-limited.
+    /// Note this remains configured on the [`Command`] for later invocations.
+    fn run_with_rlimits(&mut self, limits: &[(Resource, u64)]) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but set the umask of the child to `mask` before
+    /// executing, so that the modes of files it creates do not depend on our own umask.
+    /// Note this remains configured on the [`Command`] for later invocations.
+    fn run_with_umask(&mut self, mask: u32) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but the file descriptor `fd` is made available to
+    /// the child as `target` (e.g. for tools accepting `--fd`-style options to pass
+    /// credentials). The caller must keep `fd` open until this returns.
+    /// Note this remains configured on the [`Command`] for later invocations.
+    fn run_with_fd(&mut self, fd: RawFd, target: RawFd) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but the child drops its supplementary groups and
+    /// changes to the provided group and then user IDs before executing (which normally
+    /// requires that we are root). If any of these fail, the child is not executed.
+    /// Note this remains configured on the [`Command`] for later invocations.
+    fn run_as(&mut self, uid: u32, gid: u32) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but the child calls `unshare()` with the provided
+    /// flags before executing, so that it runs in e.g. a new mount namespace and cannot
+    /// change the host mount table. Creating most namespaces requires `CAP_SYS_ADMIN`,
+    /// unless [`NamespaceFlags::NEWUSER`] is included and unprivileged user namespaces
+    /// are permitted; if `unshare()` fails, an error naming the flags is returned.
+    /// Note this remains configured on the [`Command`] for later invocations.
+    fn run_in_namespace(&mut self, flags: NamespaceFlags) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but execute the child in the directory `dir`. If the
+    /// child could not be spawned because e.g. `dir` does not exist or is not accessible,
+    /// the error names the directory.
+    /// Note this remains configured on the [`Command`] for later invocations.
+    fn run_in_dir(&mut self, dir: &Path) -> Result<()>;
+
+    /// Execute the child and return its exit status, without checking it; an error is
+    /// only returned if the child could not be executed. Stderr is still captured
+    /// rather than inherited, and its trailing content is logged at debug level if
+    /// the child was unsuccessful.
+    fn run_status(&mut self) -> Result<ExitStatus>;
+
+    /// Execute the child, capturing its full stdout and stderr (with invalid UTF-8
+    /// replaced lossily). Like [`CommandRunExt::run_status`], an error is only returned
+    /// if the child could not be executed; checking the status is left to the caller.
+    fn run_capturing(&mut self) -> Result<CapturedOutput>;
+
+    /// A lower-level building block: spawn the child with its stderr captured as for
+    /// [`CommandRunExt::run`], returning it (e.g. so that its PID can be registered with a watchdog
+    /// or cgroup) along with the handles needed to wait for it and check its status
+    /// via [`CaptureHandles::wait`].
+    fn spawn_capturing(&mut self) -> Result<(Child, CaptureHandles)>;
+
+    /// Like [`CommandRunExt::run`], but the child's stdout is written to a file created
+    /// (or truncated) at `path`. If the child fails, the partially written file is removed.
+    fn run_stdout_to_file(&mut self, path: &Path) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but if the child succeeds, return its full stderr
+    /// (with invalid UTF-8 replaced lossily), e.g. so that warnings can be surfaced.
+    fn run_capturing_stderr(&mut self) -> Result<String>;
+
+    /// Execute the child, returning its exit status and the lines of its full stderr
+    /// (with invalid UTF-8 replaced lossily), e.g. for tools which write structured
+    /// diagnostics there. Like [`CommandRunExt::run_status`], an error is only returned
+    /// if the child could not be executed.
+    fn run_collecting_stderr_lines(&mut self) -> Result<(ExitStatus, Vec<String>)>;
+
+    /// For probe-style checks: execute the child, returning its exit code and full
+    /// stdout (as for [`CommandRunExt::run_capturing`]). If the child was killed by a
+    /// signal, the code is 128 plus the signal number, as in the shell. As with
+    /// [`CommandRunExt::run_status`], an error is only returned if the child could
+    /// not be executed.
+    fn probe(&mut self) -> Result<(i32, String)>;
+
+    /// Like [`CommandRunExt::run`], but the provided nonzero exit codes are also
+    /// treated as success. The exit code of the child is returned.
+    fn run_allowing(&mut self, allowed: &[i32]) -> Result<i32>;
+
+    /// Like [`CommandRunExt::run`], but if the child exits with one of the codes in
+    /// `map`, the error is wrapped with the corresponding human-readable label
+    /// (e.g. podman's 127 for "container command not found").
+    fn run_mapping(&mut self, map: &[(i32, &'static str)]) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but any error is passed through `f`, so that a
+    /// subsystem can consistently annotate the errors of the commands it runs (e.g.
+    /// via [`anyhow::Error::context`], which keeps the original error downcastable).
+    fn run_mapping_err(&mut self, f: impl FnOnce(anyhow::Error) -> anyhow::Error) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but on failure also emit a `tracing` error event
+    /// with the `program`, `exit_code` (if it exited) and trailing `stderr` as
+    /// structured fields, so failures can be queried in log backends.
+    fn run_observed(&mut self) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but the child's `argv[0]` is set to `arg0` (while
+    /// still executing the same program), e.g. so that process listings show which
+    /// phase of an operation spawned it.
+    /// Note this remains configured on the [`Command`] for later invocations.
+    fn run_with_arg0(&mut self, arg0: &str) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but execute the child in a transient systemd scope
+    /// unit named `scope_name` (via `systemd-run --scope`), so that it is accounted and
+    /// cleaned up by systemd. The program, arguments, environment and working directory
+    /// are preserved, but other configuration (such as stdio) is not. If the system was
+    /// not booted with systemd (or `systemd-run` is missing), the child is run directly.
+    fn run_in_scope(&mut self, scope_name: &str) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but watch the child's stderr as it is written, and
+    /// if a line matches `pattern` (e.g. an unrecoverable authentication failure),
+    /// terminate the child immediately and return an error including that line.
+    fn run_until_stderr_matches(&mut self, pattern: &regex::Regex) -> Result<()>;
+
+    /// Execute the child process, parsing its stdout as JSON. This uses `run` internally
+    /// and will return an error if the child process exits abnormally; in that case the
+    /// trailing content of stdout is included in the [`CommandError`].
+    fn run_and_parse_json<T: serde::de::DeserializeOwned>(&mut self) -> Result<T>;
+
+    /// Like [`CommandRunExt::run_and_parse_json`], but rather than deserializing the
+    /// whole of stdout, pass a (buffered) reader for it to `f`, e.g. for streaming or
+    /// partial deserialization.
+    fn run_and_parse_json_with<T, F: FnOnce(&mut dyn Read) -> Result<T>>(
+        &mut self,
+        f: F,
+    ) -> Result<T>;
+
+    /// Like [`CommandRunExt::run_and_parse_json`], but first verify that the top-level
+    /// `version` (or `schemaVersion`) field of the output matches `expected_version`,
+    /// to catch changes in the output format of the child.
+    fn run_and_parse_json_versioned<T: serde::de::DeserializeOwned>(
+        &mut self,
+        expected_version: &str,
+    ) -> Result<T>;
+
+    /// Like [`CommandRunExt::run_and_parse_json`], but if the child succeeds without
+    /// writing anything (other than whitespace) to stdout, return `T::default()`.
+    fn run_and_parse_json_or_default<T: serde::de::DeserializeOwned + Default>(
+        &mut self,
+    ) -> Result<T>;
+
+    /// Like [`CommandRunExt::run_and_parse_json`], but parse stdout as YAML.
+    /// As with JSON, empty output is an error.
+    fn run_and_parse_yaml<T: serde::de::DeserializeOwned>(&mut self) -> Result<T>;
+
+    /// Like [`CommandRunExt::run_and_parse_json`], but parse the child's stderr
+    /// instead, for tools which output their result there.
+    fn run_and_parse_json_from_stderr<T: serde::de::DeserializeOwned>(&mut self) -> Result<T>;
+
+    /// Like [`CommandRunExt::run_and_parse_json`], but for newline-delimited JSON;
+    /// each non-empty line of stdout is parsed as a separate value.
+    fn run_and_parse_json_lines<T: serde::de::DeserializeOwned>(&mut self) -> Result<Vec<T>>;
+
+    /// Like [`CommandRunExt::run_and_parse_json_lines`], but for tools which report
+    /// progress followed by a final result. The last non-empty line of stdout is parsed
+    /// as the result `R`, and each line before it as a progress event `P`, which is
+    /// passed to `on_progress` once the next line is written (as only then is it known
+    /// not to be the last, even if `P` and `R` could parse the same line).
+    fn run_with_json_progress<P, R>(&mut self, on_progress: impl FnMut(P)) -> Result<R>
+    where
+        P: serde::de::DeserializeOwned,
+        R: serde::de::DeserializeOwned;
+
+    /// Like [`CommandRunExt::run_and_parse_json`], but for a stream of concatenated
+    /// JSON values (optionally separated by whitespace). On failure, the error includes
+    /// the byte offset in stdout of the value which could not be parsed.
+    fn run_and_parse_json_stream<T: serde::de::DeserializeOwned>(&mut self) -> Result<Vec<T>>;
+
+    /// Like [`CommandRunExt::run_and_parse_json`], but buffer at most `max_bytes` of
+    /// stdout; if the child writes more than that, it is killed and an error returned,
+    /// rather than risking exhausting memory or disk with runaway output.
+    fn run_and_parse_json_bounded<T: serde::de::DeserializeOwned>(
+        &mut self,
+        max_bytes: usize,
+    ) -> Result<T>;
+
+    /// Like [`CommandRunExt::run_and_parse_json`], but for tools which occasionally
+    /// return stale or partial data: run the child up to a total of `attempts` times
+    /// until it succeeds and its output passes `valid`. If no attempt does, the result
+    /// of the final attempt is returned (even if it did not pass validation).
+    fn run_and_parse_json_validated<T: serde::de::DeserializeOwned>(
+        &mut self,
+        attempts: u32,
+        valid: impl Fn(&T) -> bool,
+    ) -> Result<T>;
+
+    /// Like [`CommandRunExt::run_and_parse_json`], but for output which is a JSON
+    /// array, whose elements are appended directly to `into`, e.g. when combining
+    /// the output of several commands. On failure, `into` is left unchanged.
+    fn run_and_merge_json<T: serde::de::DeserializeOwned>(
+        &mut self,
+        into: &mut Vec<T>,
+    ) -> Result<()>;
+
+    /// Execute the child process, returning its stdout as a string. Invalid UTF-8
+    /// is replaced lossily (see [`String::from_utf8_lossy`]); as with `run`, an
+    /// error is returned if the child process exits abnormally.
+    fn run_and_capture_stdout(&mut self) -> Result<String>;
+
+    /// Like [`CommandRunExt::run_and_capture_stdout`], but return an error (with the
+    /// byte offset of the first invalid sequence) if stdout is not valid UTF-8, e.g.
+    /// when it will be used as a path or version.
+    fn run_and_capture_stdout_strict(&mut self) -> Result<String>;
+
+    /// Execute the child, returning an error if it fails, or if its stdout (with
+    /// leading and trailing whitespace trimmed) is not `expected`; in that case
+    /// the error includes a line-based diff, e.g. for health checks.
+    fn run_expecting_stdout(&mut self, expected: &str) -> Result<()>;
+
+    /// Execute the child, parsing each non-empty line of its stdout as `KEY=VALUE`
+    /// (as output by e.g. `systemctl show` or in `os-release`). Values may be quoted
+    /// with `"` or `'`, in which case the quotes (and within double quotes, backslash
+    /// escapes) are removed. Lines starting with `#` are ignored.
+    fn run_and_parse_env(&mut self) -> Result<HashMap<String, String>>;
+
+    /// Execute the child, parsing its stdout as a list of paths, separated by NUL
+    /// if `nul_separated` (as output by e.g. `find -print0`, which is robust against
+    /// paths containing newlines) or otherwise by newlines. Empty entries are skipped,
+    /// and paths need not be valid UTF-8.
+    fn run_and_parse_paths(&mut self, nul_separated: bool) -> Result<Vec<PathBuf>>;
+
+    /// Execute the child, returning at most the first `n` bytes of its stdout. After
+    /// those have been read, we close our end of the pipe (without waiting for further
+    /// output), so if the child writes more it receives `SIGPIPE` (or `EPIPE`); being
+    /// killed by `SIGPIPE` in that case is not treated as a failure. Otherwise, as with
+    /// `run`, an error is returned if the child exits unsuccessfully.
+    fn run_peek_stdout(&mut self, n: usize) -> Result<Vec<u8>>;
+
+    /// Execute the child, invoking the callback for each line of its stdout (without
+    /// the trailing newline, and with invalid UTF-8 replaced lossily) as it is written.
+    /// As with `run`, an error is returned if the child exits unsuccessfully.
+    fn run_streaming_lines(&mut self, f: impl FnMut(&str)) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but write the provided buffer to the child's
+    /// stdin, which is then closed.
+    fn run_with_stdin(&mut self, input: &[u8]) -> Result<()>;
+
+    /// Like [`CommandRunExt::run_with_stdin`], but stream the content of `r` to the
+    /// child's stdin (from a separate thread), e.g. for large files.
+    fn run_with_stdin_reader(&mut self, r: impl Read + Send + 'static) -> Result<()>;
+
+    /// Like [`CommandRunExt::run`], but the child's stdout and stderr are merged (as with
+    /// `2>&1`), and each line is forwarded to the systemd journal, with the program name
+    /// as its syslog identifier. If the child fails, the error includes the trailing
+    /// merged output in place of stderr.
+    /// Note that afterwards stdout and stderr of the [`Command`] are set to null.
+    fn run_journaled(&mut self) -> Result<()>;
+
+    /// Execute the child with a newly allocated pseudo-terminal as its stdin, stdout
+    /// and stderr (and controlling terminal), for tools which behave differently when
+    /// attached to a terminal, returning all of its output. Since both stdout and
+    /// stderr are written to the same terminal, they are interleaved in the order the
+    /// child wrote them, and cannot be distinguished; also, the terminal translates
+    /// newlines to `\r\n`. If the child fails, the error includes the trailing output
+    /// in place of stderr.
+    /// Note that afterwards stdin, stdout and stderr of the [`Command`] are set to null,
+    /// and that the session setup remains configured for later invocations.
+    fn run_with_pty(&mut self) -> Result<String>;
+
+    /// Return the path to the executable which would be run for this command, searching
+    /// `PATH` (as set on the [`Command`], or otherwise our own) if the program name does
+    /// not contain a `/`. This gives a clearer error than executing a missing program;
+    /// e.g. "program `skopeo` not found in PATH".
+    fn ensure_exists(&self) -> Result<PathBuf>;
+
+    /// Log a warning if any argument contains shell metacharacters such as `$` or `|`,
+    /// which likely means it was mistakenly expected to be interpreted by a shell. This
+    /// does not affect execution; the return value is whether a warning was logged.
+    fn warn_on_shell_metachars(&self) -> bool;
+}
+
+/// The result of [`CommandRunExt::run_capturing`].
+#[derive(Debug)]
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) struct CapturedOutput {
+    /// The exit status of the child
+    pub(crate) status: ExitStatus,
+    /// The stdout of the child
+    pub(crate) stdout: String,
+    /// The stderr of the child
+    pub(crate) stderr: String,
+}
+
+/// The captured output of a child spawned via [`CommandRunExt::spawn_capturing`].
+#[derive(Debug)]
+pub(crate) struct CaptureHandles {
+    stderr: File,
+    command: Option<String>,
+    /// Discards excess stderr while the child executes
+    _guard: StderrGuard,
+}
+
+#[allow(dead_code)] // Not used outside tests yet
+impl CaptureHandles {
+    /// Wait for the child to exit, and return an error (including the trailing
+    /// content of its stderr) if it exited unsuccessfully.
+    pub(crate) fn wait(self, child: &mut Child) -> Result<()> {
+        let st = child.wait()?;
+        tracing::trace!("exited: {st:?}");
+        st.check_status(self.stderr).map_err(|mut e| {
+            e.command = self.command;
+            e
+        })?;
+        Ok(())
+    }
+}
+
+/// Helpers intended for [`std::process::ExitStatus`].
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) trait ExitStatusExt {
+    /// If the exit status signals it was not successful, return an error
+    /// which includes the trailing content of the provided stderr file.
+    fn check_status(&self, stderr: File) -> std::result::Result<(), CommandError> {
+        self.check_status_with_stderr_limit(stderr, MAX_STDERR_BYTES)
+    }
+
+    /// Like [`ExitStatusExt::check_status`], but retain up to `limit` trailing bytes of stderr.
+    fn check_status_with_stderr_limit(
+        &self,
+        stderr: File,
+        limit: usize,
+    ) -> std::result::Result<(), CommandError>;
+}
+
+/// A child process exited unsuccessfully.
+#[derive(Debug)]
+pub(crate) struct CommandError {
+    /// The exit status of the child
+    pub(crate) status: ExitStatus,
+    /// The trailing content of the child's stderr, with invalid UTF-8 replaced lossily
+    pub(crate) stderr: String,
+    stderr_bytes: Vec<u8>,
+    /// For [`CommandRunExt::run_and_parse_json`], the trailing content of the
+    /// child's stdout; some tools describe their failure there rather than on stderr.
+    pub(crate) stdout: Option<String>,
+    /// The redacted command line, if enabled via [`set_verbose_errors`]
+    pub(crate) command: Option<String>,
+    /// If the child dumped core, the program it was piped to (see [`core_dump_handler`]);
+    /// boxed to keep the error small
+    core_dump_handler: Option<Box<str>>,
+}
+
+#[allow(dead_code)] // Not used outside tests yet
+impl CommandError {
+    fn new(status: ExitStatus, stderr_bytes: Vec<u8>) -> Self {
+        Self {
+            status,
+            stderr: String::from_utf8_lossy(&stderr_bytes).into_owned(),
+            stderr_bytes,
+            stdout: None,
+            command: None,
+            // Determined now, as Display shouldn't do I/O
+            core_dump_handler: status
+                .core_dumped()
+                .then(core_dump_handler)
+                .flatten()
+                .map(String::into_boxed_str),
+        }
+    }
+
+    /// If enabled via [`set_verbose_errors`], record the (redacted) command line.
+    fn with_cmdline(self, cmd: &Command) -> Self {
+        match verbose_errors() {
+            true => self.record_cmdline(cmd),
+            false => self,
+        }
+    }
+
+    /// Record the (redacted) command line; see [`CommandError::with_cmdline`].
+    fn record_cmdline(mut self, cmd: &Command) -> Self {
+        self.command = Some(redacted_cmdline(cmd, &[]));
+        self
+    }
+
+    /// The trailing content of the child's stdout parsed as JSON, if it was
+    /// captured and is entirely a JSON value (e.g. an error object).
+    pub(crate) fn stdout_json(&self) -> Option<serde_json::Value> {
+        serde_json::from_str(self.stdout.as_deref()?).ok()
+    }
+
+    /// The trailing content of the child's stderr, exactly as it was written.
+    pub(crate) fn stderr_bytes(&self) -> &[u8] {
+        &self.stderr_bytes
+    }
+
+    /// The exit code of the child, if it exited normally.
+    pub(crate) fn code(&self) -> Option<i32> {
+        self.status.code()
+    }
+
+    /// Whether the child was terminated by a signal.
+    pub(crate) fn signalled(&self) -> bool {
+        self.status.signal().is_some()
+    }
+
+    /// Whether the child was terminated by a signal and produced a core dump.
+    pub(crate) fn core_dumped(&self) -> bool {
+        self.status.core_dumped()
+    }
+}
+
+/// If core dumps are piped to a program (e.g. `systemd-coredump`), return its name.
+fn core_dump_handler() -> Option<String> {
+    let pattern = std::fs::read_to_string("/proc/sys/kernel/core_pattern").ok()?;
+    let program = pattern.strip_prefix('|')?.split_whitespace().next()?;
+    let name = Path::new(program).file_name()?;
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// Return the conventional name of the provided signal, if known.
+fn signal_name(signal: i32) -> Option<&'static str> {
+    let r = match signal {
+        libc::SIGHUP => "SIGHUP",
+        libc::SIGINT => "SIGINT",
+        libc::SIGQUIT => "SIGQUIT",
+        libc::SIGILL => "SIGILL",
+        libc::SIGTRAP => "SIGTRAP",
+        libc::SIGABRT => "SIGABRT",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGFPE => "SIGFPE",
+        libc::SIGKILL => "SIGKILL",
+        libc::SIGUSR1 => "SIGUSR1",
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGUSR2 => "SIGUSR2",
+        libc::SIGPIPE => "SIGPIPE",
+        libc::SIGALRM => "SIGALRM",
+        libc::SIGTERM => "SIGTERM",
+        libc::SIGXCPU => "SIGXCPU",
+        libc::SIGXFSZ => "SIGXFSZ",
+        libc::SIGSYS => "SIGSYS",
+        _ => return None,
+    };
+    Some(r)
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status.signal() {
+            Some(signal) => match signal_name(signal) {
+                Some(name) => write!(f, "Subprocess killed by signal {name} ({signal})")?,
+                None => write!(f, "Subprocess killed by signal {signal}")?,
+            },
+            None => write!(f, "Subprocess failed: {:?}", self.status)?,
+        }
+        if self.core_dumped() {
+            match self.core_dump_handler.as_deref() {
+                Some(handler) => write!(f, " (core dumped, handled by {handler})")?,
+                None => write!(f, " (core dumped)")?,
+            }
+        }
+        write!(f, "\n{}", self.stderr)?;
+        // Start each further part on a new line, even if the output didn't end with one
+        let sep = |prev: &str| match prev.is_empty() || prev.ends_with('\n') {
+            true => "",
+            false => "\n",
+        };
+        let prev = match self.stdout.as_deref() {
+            Some(stdout) if !stdout.is_empty() => {
+                write!(f, "{}stdout: {stdout}", sep(&self.stderr))?;
+                stdout
+            }
+            _ => &self.stderr,
+        };
+        match self.command.as_deref() {
+            Some(command) => write!(f, "{}command: {command}", sep(prev)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+/// Returned (wrapped in [`anyhow::Error`]) when a child process was killed
+/// because it exceeded its timeout.
+#[derive(Debug)]
+pub(crate) struct CommandTimedOut {
+    /// The timeout which was exceeded
+    pub(crate) after: Duration,
+    /// The trailing content of the child's stderr
+    pub(crate) stderr: String,
+}
+
+impl std::fmt::Display for CommandTimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Subprocess timed out after {:?}\n{}",
+            self.after, self.stderr
+        )
+    }
+}
+
+impl std::error::Error for CommandTimedOut {}
+
+/// Returned (wrapped in [`anyhow::Error`]) when a child process was killed
+/// because its operation was cancelled.
+#[derive(Debug)]
+pub(crate) struct CommandCancelled {
+    /// The trailing content of the child's stderr
+    pub(crate) stderr: String,
+}
+
+impl std::fmt::Display for CommandCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Subprocess cancelled\n{}", self.stderr)
+    }
+}
+
+impl std::error::Error for CommandCancelled {}
+
+/// If `buf` starts in the middle of a (valid) UTF-8 sequence, skip to the start
+/// of the next character. At most 3 bytes are skipped, so genuinely invalid
+/// content is otherwise left alone.
+fn skip_partial_utf8_char(buf: &[u8]) -> &[u8] {
+    let is_continuation = |b: &u8| (b & 0b1100_0000) == 0b1000_0000;
+    let n = buf
+        .iter()
+        .take(3)
+        .take_while(|b| is_continuation(b))
+        .count();
+    &buf[n..]
+}
+
+/// Invoke `f` until it does not fail with `EINTR`, i.e. retrying if it was
+/// interrupted by signal delivery.
+fn retry_interrupted<T>(mut f: impl FnMut() -> std::io::Result<T>) -> std::io::Result<T> {
+    loop {
+        match f() {
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            r => return r,
+        }
+    }
+}
+
+/// A reader which transparently retries reads interrupted by signal delivery,
+/// for consumers (such as deserializers) which would treat `EINTR` as fatal.
+#[derive(Debug)]
+struct RetryInterrupted<R>(R);
+
+impl<R: Read> Read for RetryInterrupted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        retry_interrupted(|| self.0.read(buf))
+    }
+}
+
+/// Return the last chunk (at most `max` bytes) of the provided file. If the
+/// file was truncated, the content starts at a UTF-8 character boundary.
+/// This function is infallible; if the file cannot be read for some
+/// reason, a copy of a static string is returned.
+fn last_content_from_file(mut f: File, max: usize) -> Vec<u8> {
+    let max = u64::try_from(max).unwrap_or(u64::MAX);
+    // We should never fail to seek()+read() here, but we
+    // also don't want to panic if we do; we can't recurse via
+    // the logging system either.
+    let r = retry_interrupted(|| f.seek(std::io::SeekFrom::End(0)))
+        .map_err(|e| {
+            tracing::warn!("failed to seek: {e}");
+        })
+        .and_then(|len| {
+            // Offsets are computed relative to the start, so there's no sign
+            // conversion; and in case the file is still being written to
+            // (e.g. by a grandchild inheriting the fd), we bound the read too.
+            let mut buf = Vec::new();
+            let start = len.saturating_sub(max);
+            retry_interrupted(|| f.seek(std::io::SeekFrom::Start(start)))
+                .and_then(|_| RetryInterrupted(&mut f).take(max).read_to_end(&mut buf))
+                .map_err(|e| {
+                    tracing::warn!("failed to read: {e}");
+                })
+                .map(|_| match start {
+                    0 => buf,
+                    _ => skip_partial_utf8_char(&buf).to_vec(),
+                })
+        });
+    r.unwrap_or_else(|()| b"<failed to read stderr>".to_vec())
+}
+
+/// Like [`last_content_from_file`], but ensure the content is UTF-8
+/// by replacing invalid sequences lossily.
+fn last_utf8_content_from_file(f: File, max: usize) -> String {
+    String::from_utf8_lossy(&last_content_from_file(f, max)).into_owned()
+}
+
+/// Like [`last_utf8_content_from_file`], but returns the first chunk of
+/// the file (or other seekable reader) instead.
+fn first_utf8_content_from_file(mut f: impl Read + Seek) -> String {
+    const MAX_BYTES: u64 = 1024;
+    let mut buf = Vec::new();
+    let r = retry_interrupted(|| f.seek(std::io::SeekFrom::Start(0))).and_then(|_| {
+        RetryInterrupted(&mut f)
+            .take(MAX_BYTES)
+            .read_to_end(&mut buf)
+    });
+    if let Err(e) = r {
+        tracing::warn!("failed to read: {e}");
+        return "<failed to read output>".to_string();
+    }
+    String::from_utf8_lossy(&buf).into_owned()
+}
+
+/// Return the content of the provided file (or other seekable reader) around a
+/// (1-based) line and column, as reported by [`serde_json::Error`].
+fn content_around_location(mut f: impl Read + Seek, line: usize, column: usize) -> String {
+    const WINDOW: usize = 80;
+    let content = f
+        .seek(std::io::SeekFrom::Start(0))
+        .and_then(|_| {
+            std::io::BufReader::new(&mut f)
+                .split(b'\n')
+                .nth(line - 1)
+                .unwrap_or_else(|| Ok(Vec::new()))
+        })
+        .map_err(|e| tracing::warn!("failed to read: {e}"));
+    let Ok(content) = content else {
+        return "<failed to read output>".to_string();
+    };
+    let column = column.saturating_sub(1).min(content.len());
+    let start = column.saturating_sub(WINDOW);
+    let end = (column + WINDOW).min(content.len());
+    let window = String::from_utf8_lossy(&content[start..end]);
+    let prefix = if start > 0 { "..." } else { "" };
+    let suffix = if end < content.len() { "..." } else { "" };
+    format!("{prefix}{window}{suffix}")
+}
+
+/// Parse the full contents of the provided file (or other seekable reader), which
+/// captured the named stream of a child, as JSON. On failure, the content around
+/// the location of the error is included.
+fn parse_json_from_file<T: serde::de::DeserializeOwned>(
+    mut f: impl Read + Seek,
+    name: &str,
+) -> Result<T> {
+    // Some tools emit a UTF-8 byte order mark, which is skipped; leading whitespace
+    // is already accepted by the deserializer.
+    const BOM: &[u8] = b"\xEF\xBB\xBF";
+    retry_interrupted(|| f.seek(std::io::SeekFrom::Start(0))).context("seek")?;
+    let mut prefix = Vec::with_capacity(BOM.len());
+    RetryInterrupted(&mut f)
+        .take(BOM.len() as u64)
+        .read_to_end(&mut prefix)
+        .context("read")?;
+    let skipped = if prefix == BOM { BOM.len() } else { 0 };
+    retry_interrupted(|| f.seek(std::io::SeekFrom::Start(skipped as u64))).context("seek")?;
+    serde_json::from_reader(std::io::BufReader::new(RetryInterrupted(&mut f))).map_err(|e| {
+        let msg = match e.line() {
+            // There is no location for I/O errors
+            0 => format!(
+                "Parsing JSON from {name}: {}",
+                first_utf8_content_from_file(&mut f)
+            ),
+            line => {
+                let column = e.column();
+                let content = match line {
+                    1 if skipped > 0 => content_around_location(&mut f, line, column + skipped)
+                        .trim_start_matches('\u{feff}')
+                        .to_owned(),
+                    _ => content_around_location(&mut f, line, column),
+                };
+                format!("Parsing JSON from {name} at line {line} column {column}: {content}")
+            }
+        };
+        anyhow::Error::new(e).context(msg)
+    })
+}
+
+impl ExitStatusExt for ExitStatus {
+    fn check_status_with_stderr_limit(
+        &self,
+        stderr: File,
+        limit: usize,
+    ) -> std::result::Result<(), CommandError> {
+        if self.success() {
+            return Ok(());
+        }
+        Err(CommandError::new(
+            *self,
+            last_content_from_file(stderr, limit),
+        ))
+    }
+}
+
+/// Retains the last bytes written to it, up to a limit; this is the
+/// in-memory equivalent of [`last_content_from_file`].
+#[derive(Debug)]
+struct TailBuffer {
+    buf: Vec<u8>,
+    max: usize,
+    truncated: bool,
+}
+
+impl TailBuffer {
+    fn new(max: usize) -> Self {
+        Self {
+            buf: Vec::new(),
+            max,
+            truncated: false,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        let skip = data.len().saturating_sub(self.max);
+        let data = &data[skip..];
+        let excess = (self.buf.len() + data.len()).saturating_sub(self.max);
+        self.truncated |= skip > 0 || excess > 0;
+        self.buf.drain(..excess);
+        self.buf.extend_from_slice(data);
+    }
+
+    /// Return the retained content; if truncated, it starts at a UTF-8 character boundary.
+    fn into_bytes(self) -> Vec<u8> {
+        if self.truncated {
+            skip_partial_utf8_char(&self.buf).to_vec()
+        } else {
+            self.buf
+        }
+    }
+
+    fn to_string_lossy(&self) -> String {
+        String::from_utf8_lossy(&self.buf).into_owned()
+    }
+}
+
+/// Copy all data from `r` to `w`, also retaining its tail in `tail`. If writing
+/// fails, we continue reading until EOF (so a child writing to `r` doesn't block)
+/// and then return the error.
+fn copy_with_tail(
+    mut r: impl Read,
+    mut w: impl Write,
+    tail: &mut TailBuffer,
+) -> std::io::Result<()> {
+    let mut buf = [0u8; 8192];
+    let mut write_err = None;
+    loop {
+        let n = match r.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        tail.push(&buf[..n]);
+        if write_err.is_none() {
+            write_err = w.write_all(&buf[..n]).err();
+        }
+    }
+    write_err.map_or(Ok(()), Err)
+}
+
+/// Wait for the child `pid` to exit without reaping it (so that its PID cannot be reused),
+/// polling until `timeout` elapses if provided. Returns whether the child exited.
+fn wait_exited(pid: Pid, timeout: Option<Duration>) -> Result<bool> {
+    use rustix::process::{WaitId, WaitidOptions};
+    let options = WaitidOptions::EXITED | WaitidOptions::NOWAIT;
+    let waitid = |options| {
+        retry_interrupted(|| {
+            rustix::process::waitid(WaitId::Pid(pid), options).map_err(std::io::Error::from)
+        })
+    };
+    let Some(timeout) = timeout else {
+        waitid(options)?;
+        return Ok(true);
+    };
+    let deadline = Instant::now() + timeout;
+    // Start out polling quickly, since most commands are short lived
+    let mut interval = Duration::from_millis(1);
+    loop {
+        if waitid(options | WaitidOptions::NOHANG)?.is_some() {
+            return Ok(true);
+        }
+        let now = Instant::now();
+        if now >= deadline {
+            return Ok(false);
+        }
+        std::thread::sleep(interval.min(deadline - now));
+        interval = (interval * 2).min(Duration::from_millis(50));
+    }
+}
+
+/// Poll the child until it exits or `timeout` elapses; in the latter
+/// case `None` is returned and the child is left running.
+fn wait_timeout(child: &mut Child, timeout: Duration) -> Result<Option<ExitStatus>> {
+    match wait_exited(Pid::from_child(child), Some(timeout))? {
+        true => Ok(Some(child.wait()?)),
+        false => Ok(None),
+    }
+}
+
+/// Send `SIGTERM` to the child `pid`, then `SIGKILL` if it hasn't exited after
+/// [`TIMEOUT_GRACE_PERIOD`]. The child is not reaped.
+fn stop_child(pid: Pid) -> Result<()> {
+    if let Err(e) = rustix::process::kill_process(pid, Signal::Term) {
+        tracing::debug!("failed to send SIGTERM: {e}");
+    }
+    if !wait_exited(pid, Some(TIMEOUT_GRACE_PERIOD))? {
+        rustix::process::kill_process(pid, Signal::Kill).context("Killing child")?;
+    }
+    Ok(())
+}
+
+/// Invoke `f`, which waits for the child `pid` to exit (without reaping it), while
+/// terminating the child (see [`stop_child`]) from a separate thread if `timeout`
+/// elapses first. Returns whether that happened.
+fn with_deadline<T>(pid: Pid, timeout: Option<Duration>, f: impl FnOnce() -> T) -> (T, bool) {
+    let Some(timeout) = timeout else {
+        return (f(), false);
+    };
+    let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+    std::thread::scope(|s| {
+        let watchdog = s.spawn(move || {
+            if done_rx.recv_timeout(timeout) != Err(std::sync::mpsc::RecvTimeoutError::Timeout) {
+                return false;
+            }
+            if let Err(e) = stop_child(pid) {
+                tracing::warn!("Failed to terminate child after {timeout:?}: {e}");
+            }
+            true
+        });
+        let r = f();
+        drop(done_tx);
+        (r, matches!(watchdog.join(), Ok(true)))
+    })
+}
+
+/// Like [`stop_child`], but signal the provided process group, and then reap
+/// the provided children (which are members of it).
+fn terminate_process_group(pgid: Pid, children: &mut [(Child, File)]) -> Result<()> {
+    if let Err(e) = rustix::process::kill_process_group(pgid, Signal::Term) {
+        tracing::debug!("failed to send SIGTERM: {e}");
+    }
+    let grace_deadline = Instant::now() + TIMEOUT_GRACE_PERIOD;
+    for (child, _) in children.iter_mut() {
+        let remaining = grace_deadline.saturating_duration_since(Instant::now());
+        if wait_timeout(child, remaining)?.is_none() {
+            if let Err(e) = rustix::process::kill_process_group(pgid, Signal::Kill) {
+                tracing::debug!("failed to send SIGKILL: {e}");
+            }
+            break;
+        }
+    }
+    for (child, _) in children.iter_mut() {
+        let st = child.wait().context("Waiting for child")?;
+        tracing::trace!("terminated: {st:?}");
+    }
+    Ok(())
+}
+
+/// Whether [`record_metrics`] is enabled; see [`set_metrics_enabled`].
+static METRICS_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The metrics collected by [`record_metrics`], keyed by program name.
+static METRICS: Mutex<BTreeMap<String, ProgramMetrics>> = Mutex::new(BTreeMap::new());
+
+/// Aggregate statistics for the executions of a program; see [`dump_metrics`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct ProgramMetrics {
+    /// The number of times the program was executed
+    pub(crate) count: u64,
+    /// The total time (from spawn until exit) spent executing it
+    pub(crate) total: Duration,
+}
+
+/// Enable or disable collecting the number of executions and total execution time of
+/// each program run via [`CommandRunExt::run`] (and the helpers built on it), e.g. for
+/// profiling. This is disabled by default, in which case there is no overhead.
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) fn set_metrics_enabled(enabled: bool) {
+    METRICS_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Return the metrics collected while enabled via [`set_metrics_enabled`], keyed by
+/// the file name of the program.
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) fn dump_metrics() -> BTreeMap<String, ProgramMetrics> {
+    METRICS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+fn record_metrics(cmd: &Command, duration: Duration) {
+    if !METRICS_ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let program = cmd.get_program();
+    let name = Path::new(program).file_name().unwrap_or(program);
+    let mut metrics = METRICS.lock().unwrap_or_else(|e| e.into_inner());
+    let entry = metrics
+        .entry(name.to_string_lossy().into_owned())
+        .or_default();
+    entry.count += 1;
+    entry.total += duration;
+}
+
+/// A callback invoked before every child is spawned; see [`set_pre_spawn_hook`].
+type PreSpawnHook = std::sync::Arc<dyn Fn(&Command) + Send + Sync>;
+
+static PRE_SPAWN_HOOK: RwLock<Option<PreSpawnHook>> = RwLock::new(None);
+
+/// Register a callback which is invoked with each [`Command`] just before it is
+/// spawned by the helpers here (including [`AsyncCommandRunExt`]), e.g. to log or
+/// count all subprocess invocations in one place. This replaces any previous hook.
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) fn set_pre_spawn_hook(hook: impl Fn(&Command) + Send + Sync + 'static) {
+    let mut v = PRE_SPAWN_HOOK.write().unwrap_or_else(|e| e.into_inner());
+    *v = Some(std::sync::Arc::new(hook));
+}
+
+/// Remove the callback registered via [`set_pre_spawn_hook`].
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) fn clear_pre_spawn_hook() {
+    *PRE_SPAWN_HOOK.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// If set, the only programs which may be executed; see [`set_allowed_programs`].
+static ALLOWED_PROGRAMS: RwLock<Option<Vec<String>>> = RwLock::new(None);
+
+/// Restrict the programs which may be executed by the helpers here to those in
+/// `programs`, as defense in depth for locked-down environments. Each entry is
+/// compared to the program as passed to [`Command::new`], and to its path as found
+/// via [`CommandRunExt::ensure_exists`]; so e.g. `/usr/bin/podman` permits executing
+/// `podman` if that is found there. Other programs fail before being spawned.
+/// For [`CommandRunExt::run_in_scope`], the wrapped program is checked rather than
+/// `systemd-run`. By default, all programs are permitted.
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) fn set_allowed_programs(programs: &[&str]) {
+    let programs = programs.iter().map(|&p| p.to_owned()).collect();
+    *ALLOWED_PROGRAMS.write().unwrap_or_else(|e| e.into_inner()) = Some(programs);
+}
+
+/// Remove the restriction from [`set_allowed_programs`].
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) fn clear_allowed_programs() {
+    *ALLOWED_PROGRAMS.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+/// Return an error if the program of `cmd` is not in `allowed`.
+fn check_program_allowed(cmd: &Command, allowed: &[String]) -> Result<()> {
+    let program = cmd.get_program();
+    if allowed.iter().any(|p| program == p.as_str()) {
+        return Ok(());
+    }
+    if let Ok(path) = cmd.ensure_exists() {
+        if allowed.iter().any(|p| path == Path::new(p)) {
+            return Ok(());
+        }
+    }
+    anyhow::bail!(
+        "Executing `{}` is not permitted by the allowed programs policy",
+        program.to_string_lossy()
+    )
+}
+
+thread_local! {
+    /// Set while executing a wrapper (see [`CommandRunExt::run_in_scope`]) whose wrapped
+    /// program was already checked against [`ALLOWED_PROGRAMS`].
+    static WRAPPER_ALLOWED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Return an error if the program of `cmd` is not permitted by [`set_allowed_programs`].
+fn check_allowed_programs(cmd: &Command) -> Result<()> {
+    let allowed = ALLOWED_PROGRAMS.read().unwrap_or_else(|e| e.into_inner());
+    match allowed.as_deref() {
+        Some(allowed) => check_program_allowed(cmd, allowed),
+        None => Ok(()),
+    }
+}
+
+/// Check the command is allowed (see [`set_allowed_programs`]), log the (redacted)
+/// command line at debug level, and invoke the pre-spawn hook.
+fn pre_spawn(cmd: &Command) -> Result<()> {
+    // This exempts only the wrapper itself, not e.g. commands run by the hook
+    if !WRAPPER_ALLOWED.take() {
+        check_allowed_programs(cmd)?;
+    }
+    tracing::debug!("exec: {}", redacted_cmdline(cmd, &[]));
+    // Don't hold the lock while calling the hook, in case it runs commands itself
+    let hook = PRE_SPAWN_HOOK
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone();
+    if let Some(hook) = hook {
+        hook(cmd);
+    }
+    Ok(())
+}
+
+/// Prepare to execute the command (see [`pre_spawn`]), then spawn the child.
+fn spawn_logged(cmd: &mut Command) -> Result<Child> {
+    pre_spawn(cmd)?;
+    Ok(cmd.spawn()?)
+}
+
+/// The number of threads started by [`spawn_within`] which are still spawning a child.
+static PENDING_SPAWNS: AtomicUsize = AtomicUsize::new(0);
+
+/// The maximum of [`PENDING_SPAWNS`], beyond which [`spawn_within`] fails immediately,
+/// so that threads stuck spawning children (e.g. on a hung filesystem) cannot accumulate.
+const MAX_PENDING_SPAWNS: usize = 8;
+
+/// Like [`spawn_logged`], but spawn the child on a separate thread, returning an error
+/// if that doesn't complete within `budget`; see [`CommandRunExt::run_with_spawn_timeout`].
+/// In that case the thread kills the child if it's eventually spawned, and as it still
+/// owns the command, `cmd` is left as a copy of it (see [`copy_command`]).
+/// This doesn't invoke [`pre_spawn`].
+fn spawn_within(cmd: &mut Command, budget: Duration) -> Result<Child> {
+    let program = cmd.get_program().to_string_lossy().into_owned();
+    if PENDING_SPAWNS.fetch_add(1, Ordering::SeqCst) >= MAX_PENDING_SPAWNS {
+        PENDING_SPAWNS.fetch_sub(1, Ordering::SeqCst);
+        anyhow::bail!("Too many children still being spawned; not spawning {program}");
+    }
+    let copy = copy_command(cmd);
+    let mut moved = std::mem::replace(cmd, copy);
+    let (tx, rx) = std::sync::mpsc::channel();
+    // Taken by whichever of us gives up on the other first
+    let tx = std::sync::Arc::new(Mutex::new(Some(tx)));
+    let thread_tx = std::sync::Arc::clone(&tx);
+    let spawned = std::thread::Builder::new().spawn(move || {
+        let r = moved.spawn();
+        PENDING_SPAWNS.fetch_sub(1, Ordering::SeqCst);
+        let tx = thread_tx.lock().unwrap_or_else(|e| e.into_inner()).take();
+        match (tx, r) {
+            (Some(tx), r) => {
+                // The receiver is only dropped after taking the sender, so this can't fail
+                let _ = tx.send((moved, r));
+            }
+            (None, Ok(mut child)) => {
+                tracing::debug!("Killing child spawned after timeout");
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            (None, Err(_)) => {}
+        }
+    });
+    if let Err(e) = spawned {
+        PENDING_SPAWNS.fetch_sub(1, Ordering::SeqCst);
+        return Err(e).context("Creating thread to spawn child");
+    }
+    let (moved, child) = match rx.recv_timeout(budget) {
+        Ok(r) => r,
+        // If we can't take the sender, the result was sent just now
+        Err(_)
+            if tx
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .take()
+                .is_none() =>
+        {
+            rx.recv()?
+        }
+        Err(_) => anyhow::bail!("Timed out after {budget:?} spawning {program}"),
+    };
+    *cmd = moved;
+    Ok(child?)
+}
+
+/// Create the `subprocess` span for executing `cmd`, which [`wait_logged`] records
+/// the exit code and duration in.
+fn subprocess_span(cmd: &Command) -> tracing::Span {
+    tracing::info_span!(
+        "subprocess",
+        program = %cmd.get_program().to_string_lossy(),
+        exit_code = tracing::field::Empty,
+        duration_ms = tracing::field::Empty,
+    )
+}
+
+/// Wait for the child of `cmd` (spawned at `start`) to exit, after invoking `f` (e.g. to
+/// handle its output). If `timeout` elapses first, the child is terminated (see
+/// [`with_deadline`]) and `None` is returned as its status. While waiting the child is
+/// registered for [`shutdown_all`], and it is only reaped once deregistered so that its
+/// PID cannot be reused while registered. The duration and exit code are recorded in
+/// `span` (see [`subprocess_span`]) and the metrics.
+fn wait_logged<T>(
+    span: &tracing::Span,
+    cmd: &Command,
+    child: &mut Child,
+    start: Instant,
+    timeout: Option<Duration>,
+    f: impl FnOnce() -> T,
+) -> Result<(T, Option<ExitStatus>)> {
+    let pid = Pid::from_child(child);
+    let (r, timed_out) = {
+        let _registration = ChildRegistration::new(child.id());
+        with_deadline(pid, timeout, || {
+            let r = f();
+            wait_exited(pid, None).map(|_| r)
+        })
+    };
+    let r = r?;
+    let duration = start.elapsed();
+    record_metrics(cmd, duration);
+    let duration_ms = u64::try_from(duration.as_millis()).unwrap_or(u64::MAX);
+    span.record("duration_ms", duration_ms);
+    let st = child.wait()?;
+    if timed_out {
+        tracing::trace!("timed out: {st:?}");
+        return Ok((r, None));
+    }
+    if let Some(code) = st.code() {
+        span.record("exit_code", code);
+    }
+    tracing::trace!("exited: {st:?}");
+    Ok((r, Some(st)))
+}
+
+/// The error for a child which [`wait_logged`] terminated as `timeout` elapsed,
+/// including the provided trailing content of its stderr.
+fn timed_out(timeout: Option<Duration>, stderr: String) -> anyhow::Error {
+    let after = timeout.expect("timed out without a timeout");
+    CommandTimedOut { after, stderr }.into()
+}
+
+/// Like [`spawn_logged`], but wait for the child to exit (see [`wait_logged`]), with
+/// the default timeout. If that elapses, the error includes the trailing content of
+/// `stderr` if provided. This happens within a `subprocess` span.
+fn status_logged(cmd: &mut Command, stderr: Option<&File>) -> Result<ExitStatus> {
+    let span = subprocess_span(cmd);
+    let _guard = span.enter();
+    pre_spawn(cmd)?;
+    let start = Instant::now();
+    let mut child = cmd.spawn()?;
+    let timeout = default_timeout();
+    match wait_logged(&span, cmd, &mut child, start, timeout, || ())? {
+        ((), Some(st)) => Ok(st),
+        ((), None) => {
+            let stderr = match stderr {
+                Some(f) => last_utf8_content_from_file(f.try_clone()?, MAX_STDERR_BYTES),
+                None => String::new(),
+            };
+            Err(timed_out(timeout, stderr))
+        }
+    }
+}
+
+/// Parse `KEY=VALUE` lines; see [`CommandRunExt::run_and_parse_env`].
+fn parse_env(s: &str) -> Result<HashMap<String, String>> {
+    let mut r = HashMap::new();
+    for (i, line) in s.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((k, v)) = line.split_once('=').filter(|(k, _)| !k.is_empty()) else {
+            anyhow::bail!("Invalid line {}: {line}", i + 1);
+        };
+        let v = match v.as_bytes() {
+            [b'"', .., b'"'] => {
+                let mut unescaped = String::new();
+                let mut chars = v[1..v.len() - 1].chars();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => unescaped.extend(chars.next()),
+                        c => unescaped.push(c),
+                    }
+                }
+                unescaped
+            }
+            [b'\'', .., b'\''] => v[1..v.len() - 1].to_owned(),
+            _ => v.to_owned(),
+        };
+        r.insert(k.to_owned(), v);
+    }
+    Ok(r)
+}
+
+/// Deserializes a sequence by appending its elements to the vector; see
+/// [`CommandRunExt::run_and_merge_json`].
+struct ExtendVec<'a, T>(&'a mut Vec<T>);
+
+impl<'de, T: serde::Deserialize<'de>> serde::de::DeserializeSeed<'de> for ExtendVec<'_, T> {
+    type Value = ();
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de, T: serde::Deserialize<'de>> serde::de::Visitor<'de> for ExtendVec<'_, T> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str("an array")
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        if let Some(n) = seq.size_hint() {
+            self.0.reserve(n);
+        }
+        while let Some(v) = seq.next_element()? {
+            self.0.push(v);
+        }
+        Ok(())
+    }
+}
+
+/// Invoke the callback for each line read from `r`.
+fn for_each_line(r: impl Read, mut f: impl FnMut(&str)) -> std::io::Result<()> {
+    find_line(r, |line| {
+        f(line);
+        false
+    })
+    .map(drop)
+}
+
+/// Invoke the predicate for each line read from `r` (without the trailing newline,
+/// and with invalid UTF-8 replaced lossily), stopping at and returning the first
+/// line for which it returns `true`.
+fn find_line(r: impl Read, mut f: impl FnMut(&str) -> bool) -> std::io::Result<Option<String>> {
+    let mut r = std::io::BufReader::new(r);
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        if r.read_until(b'\n', &mut buf)? == 0 {
+            return Ok(None);
+        }
+        if buf.last() == Some(&b'\n') {
+            buf.pop();
+        }
+        let line = String::from_utf8_lossy(&buf);
+        if f(&line) {
+            return Ok(Some(line.into_owned()));
+        }
+    }
+}
+
+/// By default, we only retain this many trailing bytes of a child's stderr
+/// to avoid pathological error messages.
+const MAX_STDERR_BYTES: usize = 1024;
+
+/// Once a file capturing stderr is larger than this, the content before the trailing
+/// part we retain is discarded, so that a child writing excessively to stderr cannot
+/// fill e.g. a small `/tmp`.
+const MAX_STDERR_CAPTURE_BYTES: u64 = 16 * 1024 * 1024;
+
+/// How often the size of a file capturing stderr is checked.
+const STDERR_GUARD_INTERVAL: Duration = Duration::from_millis(100);
+
+/// If `stderr` is larger than [`MAX_STDERR_CAPTURE_BYTES`], deallocate all but its
+/// trailing `keep` bytes (leaving a hole, so the size and offsets are unchanged).
+/// Returns whether this was necessary.
+fn discard_excess_stderr(stderr: &File, keep: u64) -> std::io::Result<bool> {
+    let len = stderr.metadata()?.len();
+    if len <= MAX_STDERR_CAPTURE_BYTES {
+        return Ok(false);
+    }
+    let flags = rustix::fs::FallocateFlags::PUNCH_HOLE | rustix::fs::FallocateFlags::KEEP_SIZE;
+    rustix::fs::fallocate(stderr, flags, 0, len.saturating_sub(keep))?;
+    Ok(true)
+}
+
+/// A file capturing stderr which is checked by the guard thread; see [`StderrGuard`].
+#[derive(Debug)]
+struct GuardedStderr {
+    file: File,
+    /// The number of trailing bytes to retain, or `None` once discarding failed
+    keep: Option<u64>,
+    warned: bool,
+}
+
+/// The files capturing stderr which are checked by the guard thread, keyed by their
+/// (duplicated) fd.
+static GUARDED_STDERR: Mutex<BTreeMap<RawFd, GuardedStderr>> = Mutex::new(BTreeMap::new());
+
+/// The body of the guard thread, which calls [`discard_excess_stderr`] for the files
+/// in [`GUARDED_STDERR`] every [`STDERR_GUARD_INTERVAL`], parking while there are none.
+fn guard_stderr() {
+    loop {
+        let mut guarded = GUARDED_STDERR.lock().unwrap_or_else(|e| e.into_inner());
+        if guarded.is_empty() {
+            drop(guarded);
+            std::thread::park();
+            continue;
+        }
+        for g in guarded.values_mut() {
+            let Some(keep) = g.keep else {
+                continue;
+            };
+            match discard_excess_stderr(&g.file, keep) {
+                Ok(true) if !g.warned => {
+                    tracing::warn!("Subprocess wrote excessively to stderr; discarding all but the trailing content");
+                    g.warned = true;
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    tracing::warn!("Failed to discard excess stderr: {e}");
+                    g.keep = None;
+                }
+            }
+        }
+        drop(guarded);
+        std::thread::sleep(STDERR_GUARD_INTERVAL);
+    }
+}
+
+/// While this exists, [`discard_excess_stderr`] is periodically called for a file
+/// capturing stderr, from a single guard thread shared by all children which is
+/// started on first use.
+#[derive(Debug)]
+struct StderrGuard(Option<RawFd>);
+
+impl StderrGuard {
+    /// Guard `stderr`, retaining its trailing `keep` bytes.
+    fn new(stderr: &File, keep: usize) -> Self {
+        static THREAD: OnceLock<Option<std::thread::Thread>> = OnceLock::new();
+        let thread = THREAD.get_or_init(|| {
+            match std::thread::Builder::new()
+                .name("stderr-guard".into())
+                .spawn(guard_stderr)
+            {
+                Ok(handle) => Some(handle.thread().clone()),
+                Err(e) => {
+                    tracing::warn!("Failed to start stderr guard thread: {e}");
+                    None
+                }
+            }
+        });
+        let Some(thread) = thread else {
+            return Self(None);
+        };
+        let file = match stderr.try_clone() {
+            Ok(f) => f,
+            Err(e) => {
+                tracing::warn!("Failed to guard stderr: {e}");
+                return Self(None);
+            }
+        };
+        let fd = std::os::fd::AsRawFd::as_raw_fd(&file);
+        let g = GuardedStderr {
+            file,
+            keep: Some(u64::try_from(keep).unwrap_or(u64::MAX)),
+            warned: false,
+        };
+        GUARDED_STDERR
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(fd, g);
+        thread.unpark();
+        Self(Some(fd))
+    }
+}
+
+impl Drop for StderrGuard {
+    fn drop(&mut self) {
+        if let Some(fd) = self.0 {
+            GUARDED_STDERR
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&fd);
+        }
+    }
+}
+
+/// Invoke `f` (which waits for a child), while excess stderr is discarded via a
+/// [`StderrGuard`].
+fn with_stderr_guard<T>(stderr: &File, keep: usize, f: impl FnOnce() -> T) -> T {
+    let _guard = StderrGuard::new(stderr, keep);
+    f()
+}
+
+/// Prefix each line of `tail`, which is the trailing content of the (complete)
+/// stderr in `f`, with its line number in `f`. If `f` cannot be read, `tail` is
+/// returned unchanged.
+fn number_stderr_lines(f: &File, tail: &str) -> String {
+    let mut newlines = 0usize;
+    let mut last = None;
+    let mut buf = [0u8; 8192];
+    let mut offset = 0;
+    loop {
+        use std::os::unix::fs::FileExt;
+        match retry_interrupted(|| f.read_at(&mut buf, offset)) {
+            Ok(0) => break,
+            Ok(n) => {
+                newlines += buf[..n].iter().filter(|&&b| b == b'\n').count();
+                last = Some(buf[n - 1]);
+                offset += n as u64;
+            }
+            Err(e) => {
+                tracing::warn!("failed to read: {e}");
+                return tail.to_owned();
+            }
+        }
+    }
+    let total = match last {
+        Some(b'\n') | None => newlines,
+        Some(_) => newlines + 1,
+    };
+    let first = (total + 1).saturating_sub(tail.lines().count()).max(1);
+    let width = total.to_string().len();
+    tail.lines()
+        .zip(first..)
+        .map(|(line, n)| format!("{n:>width$}: {line}\n"))
+        .collect()
+}
+
+/// Check the status of a child executed by [`run_impl`], and if it was unsuccessful
+/// return the error including its stderr.
+fn run_impl_check_status(
+    cmd: &Command,
+    st: ExitStatus,
+    stderr: File,
+    stderr_limit: usize,
+    line_numbers: bool,
+) -> Result<()> {
+    let numbered_from = match line_numbers && !st.success() {
+        true => Some(stderr.try_clone()?),
+        false => None,
+    };
+    st.check_status_with_stderr_limit(stderr, stderr_limit)
+        .map_err(|mut e| {
+            if let Some(f) = numbered_from {
+                e.stderr = number_stderr_lines(&f, &e.stderr);
+            }
+            e.with_cmdline(cmd).into()
+        })
+}
+
+/// The implementation of [`CommandRunExt::run`] and its variants with a timeout
+/// (for spawning via `spawn_budget`, or executing via `timeout`) and/or a non-default
+/// stderr limit, or with line numbers in the stderr of the error (see
+/// [`RunConfig::stderr_line_numbers`]).
+fn run_impl(
+    cmd: &mut Command,
+    timeout: Option<Duration>,
+    stderr_limit: usize,
+    line_numbers: bool,
+    spawn_budget: Option<Duration>,
+) -> Result<()> {
+    if dry_run_skip(cmd) {
+        return Ok(());
+    }
+    let stderr = capture_tempfile()?;
+    cmd.stderr(stderr.try_clone()?);
+    let span = subprocess_span(cmd);
+    let _guard = span.enter();
+    pre_spawn(cmd)?;
+    let start = Instant::now();
+    let mut child = match spawn_budget {
+        Some(budget) => spawn_within(cmd, budget)?,
+        None => cmd.spawn()?,
+    };
+    let r = with_stderr_guard(&stderr, stderr_limit, || {
+        wait_logged(&span, cmd, &mut child, start, timeout, || ())
+    })?;
+    let ((), Some(st)) = r else {
+        let stderr = last_utf8_content_from_file(stderr, stderr_limit);
+        return Err(timed_out(timeout, stderr));
+    };
+    run_impl_check_status(cmd, st, stderr, stderr_limit, line_numbers)
+}
+
+/// Return a diff of the lines (via their longest common subsequence), with
+/// each prefixed by `-` if only in `expected`, `+` if only in `actual`, or
+/// otherwise a space.
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) fn diff_lines(expected: &[&str], actual: &[&str]) -> String {
+    let (n, m) = (expected.len(), actual.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if expected[i] == actual[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && expected[i] == actual[j] {
+            diff.push_str(&format!(" {}\n", expected[i]));
+            (i, j) = (i + 1, j + 1);
+        } else if j == m || (i < n && lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push_str(&format!("-{}\n", expected[i]));
+            i += 1;
+        } else {
+            diff.push_str(&format!("+{}\n", actual[j]));
+            j += 1;
+        }
+    }
+    diff
+}
+
+/// Return a command which executes `cmd` in a transient systemd scope; see
+/// [`CommandRunExt::run_in_scope`].
+fn scope_command(cmd: &Command, scope_name: &str) -> Command {
+    let mut scoped = Command::new("systemd-run");
+    scoped
+        .args(["--scope", "--quiet", "--collect", "--unit"])
+        .arg(scope_name)
+        .arg("--")
+        .arg(cmd.get_program())
+        .args(cmd.get_args());
+    copy_env_and_dir(cmd, &mut scoped);
+    scoped
+}
+
+/// Return a command with the program, arguments, environment and working directory
+/// of `cmd` (see [`copy_env_and_dir`]), but none of its other configuration, such as
+/// its stdio or `pre_exec` hooks.
+fn copy_command(cmd: &Command) -> Command {
+    let mut copy = Command::new(cmd.get_program());
+    copy.args(cmd.get_args());
+    copy_env_and_dir(cmd, &mut copy);
+    copy
+}
+
+/// Apply the environment variables explicitly set on (or removed from) `from`, and its
+/// working directory, to `to`. Note a cleared environment cannot be detected.
+fn copy_env_and_dir(from: &Command, to: &mut Command) {
+    for (k, v) in from.get_envs() {
+        match v {
+            Some(v) => to.env(k, v),
+            None => to.env_remove(k),
+        };
+    }
+    if let Some(dir) = from.get_current_dir() {
+        to.current_dir(dir);
+    }
+}
+
+/// Clear the environment of the command, except for `PATH`, the variables
+/// named in `keep`, and those explicitly set on the command.
+fn scrub_env(cmd: &mut Command, keep: &[&str]) {
+    let explicit: Vec<_> = cmd
+        .get_envs()
+        .filter_map(|(k, v)| Some((k.to_owned(), v?.to_owned())))
+        .collect();
+    cmd.env_clear();
+    for k in std::iter::once("PATH").chain(keep.iter().copied()) {
+        if let Some(v) = std::env::var_os(k) {
+            cmd.env(k, v);
+        }
+    }
+    cmd.envs(explicit);
+}
+
+/// Execute the child with its stdout directed to a tempfile, and return that
+/// file (rewound to the start) if the child exited successfully.
+fn run_capturing_stdout_file(cmd: &mut Command) -> Result<File> {
+    ensure_not_dry_run()?;
+    let mut stdout = capture_tempfile()?;
+    cmd.stdout(stdout.try_clone()?);
+    cmd.run()?;
+    stdout.seek(std::io::SeekFrom::Start(0)).context("seek")?;
+    Ok(stdout)
+}
+
+/// The implementation of [`CommandRunExt::run_with_stdin`] and
+/// [`CommandRunExt::run_with_stdin_reader`].
+fn run_with_stdin_impl(cmd: &mut Command, mut input: impl Read + Send) -> Result<()> {
+    if dry_run_skip(cmd) {
+        return Ok(());
+    }
+    let stderr = capture_tempfile()?;
+    cmd.stderr(stderr.try_clone()?);
+    cmd.stdin(Stdio::piped());
+    let span = subprocess_span(cmd);
+    let _guard = span.enter();
+    let start = Instant::now();
+    let mut child = spawn_logged(cmd)?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let timeout = default_timeout();
+    // Write from a separate thread so that we're concurrently waiting for the child;
+    // stdin is closed when the thread is done.
+    let (st, written) = std::thread::scope(|s| -> Result<_> {
+        let writer = s.spawn(move || match std::io::copy(&mut input, &mut stdin) {
+            // The child is free to exit without reading all of its input
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+            r => r.map(drop),
+        });
+        let ((), st) = with_stderr_guard(&stderr, MAX_STDERR_BYTES, || {
+            wait_logged(&span, cmd, &mut child, start, timeout, || ())
+        })?;
+        let written = writer
+            .join()
+            .map_err(|e| anyhow::anyhow!("Failed to join thread: {e:?}"))?;
+        Ok((st, written))
+    })?;
+    let Some(st) = st else {
+        let stderr = last_utf8_content_from_file(stderr, MAX_STDERR_BYTES);
+        return Err(timed_out(timeout, stderr));
+    };
+    st.check_status(stderr).map_err(|e| e.with_cmdline(cmd))?;
+    written.context("Writing to stdin")
+}
+
+impl CommandRunExt for Command {
+    fn run(&mut self) -> Result<()> {
+        self.run_with_stderr_limit(MAX_STDERR_BYTES)
+    }
+
+    fn run_quiet(&mut self) -> Result<()> {
+        self.stdout(null_sink()?);
+        self.run()
+    }
+
+    fn run_with_stderr_limit(&mut self, limit: usize) -> Result<()> {
+        run_impl(self, default_timeout(), limit, false, None)
+    }
+
+    fn run_with_timeout(&mut self, timeout: Duration) -> Result<()> {
+        run_impl(self, Some(timeout), MAX_STDERR_BYTES, false, None)
+    }
+
+    fn run_with_spawn_timeout(&mut self, budget: Duration) -> Result<()> {
+        run_impl(
+            self,
+            default_timeout(),
+            MAX_STDERR_BYTES,
+            false,
+            Some(budget),
+        )
+    }
+
+    fn run_with_stderr(&mut self, w: impl Write) -> Result<()> {
+        if dry_run_skip(self) {
+            return Ok(());
+        }
+        self.stderr(Stdio::piped());
+        let span = subprocess_span(self);
+        let _guard = span.enter();
+        let start = Instant::now();
+        let mut child = spawn_logged(self)?;
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let mut tail = TailBuffer::new(MAX_STDERR_BYTES);
+        let timeout = default_timeout();
+        let (r, st) = wait_logged(&span, self, &mut child, start, timeout, || {
+            copy_with_tail(stderr, w, &mut tail)
+        })?;
+        let Some(st) = st else {
+            return Err(timed_out(timeout, tail.to_string_lossy()));
+        };
+        if !st.success() {
+            return Err(CommandError::new(st, tail.into_bytes())
+                .with_cmdline(self)
+                .into());
+        }
+        r.context("Copying stderr")
+    }
+
+    fn run_piped(&mut self) -> Result<()> {
+        self.run_with_stderr(std::io::sink())
+    }
+
+    fn run_timed(&mut self) -> Result<Duration> {
+        if dry_run_skip(self) {
+            return Ok(Duration::ZERO);
+        }
+        let stderr = capture_tempfile()?;
+        self.stderr(stderr.try_clone()?);
+        let start = Instant::now();
+        let st = with_stderr_guard(&stderr, MAX_STDERR_BYTES, || {
+            status_logged(self, Some(&stderr))
+        })?;
+        let elapsed = start.elapsed();
+        tracing::debug!("elapsed: {elapsed:?}");
+        st.check_status(stderr).map_err(|e| e.with_cmdline(self))?;
+        Ok(elapsed)
+    }
+
+    fn run_with_retries(&mut self, attempts: u32, backoff: Duration) -> Result<()> {
+        self.run_with_retries_if(attempts, backoff, None, |_| true)
+    }
+
+    fn run_with_retries_if(
+        &mut self,
+        attempts: u32,
+        backoff: Duration,
+        timeout: Option<Duration>,
+        should_retry: impl Fn(&anyhow::Error) -> bool,
+    ) -> Result<()> {
+        let mut backoff = backoff;
+        let mut attempt = 1;
+        loop {
+            let r = match timeout {
+                Some(timeout) => self.run_with_timeout(timeout),
+                None => self.run(),
+            };
+            match r {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt < attempts && should_retry(&e) => {
+                    tracing::warn!("Attempt {attempt}/{attempts} failed, retrying: {e}");
+                    std::thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn run_clean_env(&mut self, keep: &[&str]) -> Result<()> {
+        scrub_env(self, keep);
+        self.run()
+    }
+
+    fn run_interactive(&mut self) -> Result<()> {
+        if dry_run_skip(self) {
+            return Ok(());
+        }
+        self.stdin(Stdio::inherit())
+            .stdout(Stdio::inherit())
+            .stderr(Stdio::inherit());
+        let span = subprocess_span(self);
+        let _guard = span.enter();
+        pre_spawn(self)?;
+        let start = Instant::now();
+        let mut child = self.spawn()?;
+        // The child may legitimately wait for the user indefinitely, so there's no timeout
+        let ((), st) = wait_logged(&span, self, &mut child, start, None, || ())?;
+        let st = st.expect("waited without a timeout");
+        if !st.success() {
+            return Err(CommandError::new(st, Vec::new()).with_cmdline(self).into());
+        }
+        Ok(())
+    }
+
+    fn run_discarding(&mut self) -> Result<()> {
+        if dry_run_skip(self) {
+            return Ok(());
+        }
+        self.stdout(null_sink()?).stderr(null_sink()?);
+        let st = status_logged(self, None)?;
+        if !st.success() {
+            return Err(CommandError::new(st, Vec::new()).with_cmdline(self).into());
+        }
+        Ok(())
+    }
+
+    #[allow(unsafe_code)]
+    fn run_in_new_session(&mut self) -> Result<()> {
+        // SAFETY: setsid() is async-signal-safe
+        unsafe {
+            self.pre_exec(|| {
+                rustix::process::setsid()?;
+                Ok(())
+            });
+        }
+        self.run()
+    }
+
+    #[allow(unsafe_code)]
+    fn run_resetting_signals(&mut self) -> Result<()> {
+        // SAFETY: Between fork and exec we may only use async-signal-safe functions,
+        // and must not allocate; signal(), sigemptyset() and sigprocmask() are safe,
+        // and the signal set lives on the stack.
+        unsafe {
+            self.pre_exec(|| {
+                for sig in 1..=libc::SIGRTMAX() {
+                    // This fails for e.g. SIGKILL, which can't be changed anyway
+                    libc::signal(sig, libc::SIG_DFL);
+                }
+                let mut set = std::mem::MaybeUninit::<libc::sigset_t>::uninit();
+                libc::sigemptyset(set.as_mut_ptr());
+                if libc::sigprocmask(libc::SIG_SETMASK, set.as_ptr(), std::ptr::null_mut()) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        self.run()
+    }
+
+    #[allow(unsafe_code)]
+    fn run_with_priority(&mut self, nice: i32) -> Result<()> {
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_CLASS_BE: libc::c_int = 2;
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+        let nice = nice.clamp(-20, 19);
+        // This is how the kernel maps nice values to best-effort levels 0 to 7
+        let ioprio = (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | ((nice + 20) / 5);
+        // The child reports each failure as a tag (`n` for the nice value, or `i` for
+        // the I/O priority) and errno over this socket; both ends are close-on-exec.
+        let (mut errors_r, errors_w) = std::os::unix::net::UnixStream::pair()?;
+        // SAFETY: setpriority(), ioprio_set() and send() are async-signal-safe, and the
+        // report lives on the stack
+        unsafe {
+            self.pre_exec(move || {
+                let report = |tag: u8, e: std::io::Error| {
+                    let mut msg = [tag, 0, 0, 0, 0];
+                    msg[1..].copy_from_slice(&e.raw_os_error().unwrap_or(0).to_ne_bytes());
+                    // Use MSG_NOSIGNAL in case the parent has closed its end
+                    libc::send(
+                        std::os::fd::AsRawFd::as_raw_fd(&errors_w),
+                        msg.as_ptr().cast(),
+                        msg.len(),
+                        libc::MSG_NOSIGNAL,
+                    );
+                };
+                if let Err(e) = rustix::process::setpriority_process(None, nice) {
+                    report(b'n', e.into());
+                }
+                if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) != 0 {
+                    report(b'i', std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let r = self.run();
+        // Anything the child sent remains buffered in the socket
+        errors_r.set_nonblocking(true)?;
+        let mut msg = [0u8; 5];
+        while let Ok(5) = errors_r.read(&mut msg) {
+            let [tag, errno @ ..] = msg;
+            let e = std::io::Error::from_raw_os_error(i32::from_ne_bytes(errno));
+            match tag {
+                b'n' => tracing::warn!("Failed to set priority {nice} for child: {e}"),
+                _ => tracing::warn!("Failed to set I/O priority for child: {e}"),
+            }
+        }
+        r
+    }
+
+    #[allow(unsafe_code)]
+    fn run_with_rlimits(&mut self, limits: &[(Resource, u64)]) -> Result<()> {
+        let owned_limits = limits.to_vec();
+        // SAFETY: setrlimit() is async-signal-safe
+        unsafe {
+            self.pre_exec(move || {
+                for &(resource, limit) in owned_limits.iter() {
+                    let limit = Some(limit);
+                    rustix::process::setrlimit(
+                        resource,
+                        rustix::process::Rlimit {
+                            current: limit,
+                            maximum: limit,
+                        },
+                    )?;
+                }
+                Ok(())
+            });
+        }
+        self.run()
+            .map_err(|e| match e.downcast_ref::<CommandError>() {
+                Some(ce) if ce.signalled() => {
+                    e.context(format!("Subprocess was resource-limited: {limits:?}"))
+                }
+                _ => e,
+            })
+    }
+
+    #[allow(unsafe_code)]
+    fn run_with_umask(&mut self, mask: u32) -> Result<()> {
+        let mask = rustix::fs::Mode::from_bits_truncate(mask);
+        // SAFETY: umask() is async-signal-safe
+        unsafe {
+            self.pre_exec(move || {
+                rustix::process::umask(mask);
+                Ok(())
+            });
+        }
+        self.run()
+    }
+
+    #[allow(unsafe_code)]
+    fn run_with_fd(&mut self, fd: RawFd, target: RawFd) -> Result<()> {
+        // SAFETY: dup2() and fcntl() are async-signal-safe, and we only reference the
+        // (copied) fd numbers.
+        unsafe {
+            self.pre_exec(move || {
+                // dup2() does not copy FD_CLOEXEC, but if the numbers are the same it
+                // is a no-op, so the flag must be cleared explicitly.
+                let r = if fd == target {
+                    libc::fcntl(fd, libc::F_SETFD, 0)
+                } else {
+                    libc::dup2(fd, target)
+                };
+                if r < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        self.run()
+    }
+
+    #[allow(unsafe_code)]
+    fn run_as(&mut self, uid: u32, gid: u32) -> Result<()> {
+        // SAFETY: setgroups(), setgid() and setuid() are async-signal-safe
+        unsafe {
+            self.pre_exec(move || {
+                // The groups must be changed first, as that is no longer permitted
+                // once we have changed to an unprivileged user.
+                if libc::setgroups(0, std::ptr::null()) < 0
+                    || libc::setgid(gid) < 0
+                    || libc::setuid(uid) < 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        self.run().map_err(|e| {
+            if e.downcast_ref::<std::io::Error>().is_some() {
+                e.context(format!("Spawning as user {uid} and group {gid}"))
+            } else {
+                e
+            }
+        })
+    }
+
+    #[allow(unsafe_code)]
+    fn run_in_namespace(&mut self, flags: NamespaceFlags) -> Result<()> {
+        // As for run_with_priority(), the child reports failure over this socket (here
+        // as just an errno).
+        let (mut errors_r, errors_w) = std::os::unix::net::UnixStream::pair()?;
+        // SAFETY: unshare() and send() are async-signal-safe
+        unsafe {
+            self.pre_exec(move || {
+                if let Err(e) = rustix::thread::unshare(flags) {
+                    let errno = e.raw_os_error().to_ne_bytes();
+                    libc::send(
+                        std::os::fd::AsRawFd::as_raw_fd(&errors_w),
+                        errno.as_ptr().cast(),
+                        errno.len(),
+                        libc::MSG_NOSIGNAL,
+                    );
+                    return Err(e.into());
+                }
+                Ok(())
+            });
+        }
+        let r = self.run();
+        if r.is_err() {
+            errors_r.set_nonblocking(true)?;
+            let mut errno = [0u8; 4];
+            if let Ok(4) = errors_r.read(&mut errno) {
+                let e = std::io::Error::from_raw_os_error(i32::from_ne_bytes(errno));
+                anyhow::bail!("Failed to unshare namespaces ({flags:?}): {e}");
+            }
+        }
+        r
+    }
+
+    fn run_in_dir(&mut self, dir: &Path) -> Result<()> {
+        self.current_dir(dir);
+        self.run()
+            .map_err(|e| match e.downcast_ref::<std::io::Error>() {
+                Some(ioe)
+                    if matches!(
+                        ioe.kind(),
+                        std::io::ErrorKind::NotFound | std::io::ErrorKind::PermissionDenied
+                    ) =>
+                {
+                    e.context(format!("Spawning in directory {}", dir.display()))
+                }
+                _ => e,
+            })
+    }
+
+    fn run_status(&mut self) -> Result<ExitStatus> {
+        if dry_run_skip(self) {
+            return Ok(ExitStatus::from_raw(0));
+        }
+        let stderr = capture_tempfile()?;
+        self.stderr(stderr.try_clone()?);
+        let st = with_stderr_guard(&stderr, MAX_STDERR_BYTES, || {
+            status_logged(self, Some(&stderr))
+        })?;
+        if !st.success() {
+            let stderr = last_utf8_content_from_file(stderr, MAX_STDERR_BYTES);
+            tracing::debug!("stderr: {stderr}");
+        }
+        Ok(st)
+    }
+
+    fn spawn_capturing(&mut self) -> Result<(Child, CaptureHandles)> {
+        ensure_not_dry_run()?;
+        let stderr = capture_tempfile()?;
+        self.stderr(stderr.try_clone()?);
+        let child = spawn_logged(self)?;
+        let command = verbose_errors().then(|| redacted_cmdline(self, &[]));
+        let _guard = StderrGuard::new(&stderr, MAX_STDERR_BYTES);
+        Ok((
+            child,
+            CaptureHandles {
+                stderr,
+                command,
+                _guard,
+            },
+        ))
+    }
+
+    fn run_capturing(&mut self) -> Result<CapturedOutput> {
+        ensure_not_dry_run()?;
+        let stdout = capture_tempfile()?;
+        self.stdout(stdout.try_clone()?);
+        let stderr = capture_tempfile()?;
+        self.stderr(stderr.try_clone()?);
+        let status = status_logged(self, Some(&stderr))?;
+        let read_all = |mut f: File, name: &str| -> Result<String> {
+            let mut buf = Vec::new();
+            f.seek(std::io::SeekFrom::Start(0))
+                .and_then(|_| f.read_to_end(&mut buf))
+                .with_context(|| format!("Reading {name}"))?;
+            Ok(String::from_utf8_lossy(&buf).into_owned())
+        };
+        Ok(CapturedOutput {
+            status,
+            stdout: read_all(stdout, "stdout")?,
+            stderr: read_all(stderr, "stderr")?,
+        })
+    }
+
+    fn run_stdout_to_file(&mut self, path: &Path) -> Result<()> {
+        if dry_run_skip(self) {
+            return Ok(());
+        }
+        let f = File::create(path).with_context(|| format!("Creating {}", path.display()))?;
+        self.stdout(f);
+        let r = self.run();
+        if r.is_err() {
+            if let Err(e) = std::fs::remove_file(path) {
+                tracing::warn!("Failed to remove {}: {e}", path.display());
+            }
+        }
+        r
+    }
+
+    fn run_capturing_stderr(&mut self) -> Result<String> {
+        ensure_not_dry_run()?;
+        let mut stderr = capture_tempfile()?;
+        self.stderr(stderr.try_clone()?);
+        status_logged(self, Some(&stderr))?
+            .check_status(stderr.try_clone()?)
+            .map_err(|e| e.with_cmdline(self))?;
+        stderr.seek(std::io::SeekFrom::Start(0)).context("seek")?;
+        let mut buf = Vec::new();
+        stderr.read_to_end(&mut buf).context("Reading stderr")?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    fn run_collecting_stderr_lines(&mut self) -> Result<(ExitStatus, Vec<String>)> {
+        ensure_not_dry_run()?;
+        let mut stderr = capture_tempfile()?;
+        self.stderr(stderr.try_clone()?);
+        let st = status_logged(self, Some(&stderr))?;
+        stderr.seek(std::io::SeekFrom::Start(0)).context("seek")?;
+        let mut lines = Vec::new();
+        for_each_line(stderr, |line| lines.push(line.to_owned())).context("Reading stderr")?;
+        Ok((st, lines))
+    }
+
+    fn probe(&mut self) -> Result<(i32, String)> {
+        let out = self.run_capturing()?;
+        let code = out
+            .status
+            .code()
+            .or_else(|| out.status.signal().map(|sig| 128 + sig))
+            .unwrap_or(-1);
+        Ok((code, out.stdout))
+    }
+
+    fn run_allowing(&mut self, allowed: &[i32]) -> Result<i32> {
+        if dry_run_skip(self) {
+            return Ok(0);
+        }
+        let stderr = capture_tempfile()?;
+        self.stderr(stderr.try_clone()?);
+        let st = with_stderr_guard(&stderr, MAX_STDERR_BYTES, || {
+            status_logged(self, Some(&stderr))
+        })?;
+        if let Some(code) = st.code().filter(|c| allowed.contains(c)) {
+            return Ok(code);
+        }
+        st.check_status(stderr).map_err(|e| e.with_cmdline(self))?;
+        Ok(0)
+    }
+
+    fn run_mapping(&mut self, map: &[(i32, &'static str)]) -> Result<()> {
+        self.run().map_err(|e| {
+            let code = e.downcast_ref::<CommandError>().and_then(|e| e.code());
+            match map.iter().find(|(c, _)| Some(*c) == code) {
+                Some((code, label)) => e.context(format!("{label} (exit code {code})")),
+                None => e,
+            }
+        })
+    }
+
+    fn run_mapping_err(&mut self, f: impl FnOnce(anyhow::Error) -> anyhow::Error) -> Result<()> {
+        self.run().map_err(f)
+    }
+
+    fn run_observed(&mut self) -> Result<()> {
+        self.run().map_err(|e| {
+            let program = self.get_program().to_string_lossy();
+            match e.downcast_ref::<CommandError>() {
+                Some(ce) => tracing::error!(
+                    program = %program,
+                    exit_code = ce.code(),
+                    stderr = %ce.stderr,
+                    "Subprocess failed"
+                ),
+                None => tracing::error!(program = %program, error = %e, "Subprocess failed"),
+            }
+            e
+        })
+    }
+
+    fn run_with_arg0(&mut self, arg0: &str) -> Result<()> {
+        self.arg0(arg0);
+        self.run()
+    }
+
+    fn run_in_scope(&mut self, scope_name: &str) -> Result<()> {
+        // Log the wrapped command, rather than systemd-run
+        if dry_run_skip(self) {
+            return Ok(());
+        }
+        let mut scoped = scope_command(self, scope_name);
+        if !libsystemd::daemon::booted() || scoped.ensure_exists().is_err() {
+            tracing::debug!("systemd is unavailable; not running in scope {scope_name}");
+            return self.run();
+        }
+        // Apply the policy to the wrapped program, rather than systemd-run
+        check_allowed_programs(self)?;
+        WRAPPER_ALLOWED.set(true);
+        let r = scoped.run();
+        WRAPPER_ALLOWED.set(false);
+        r
+    }
+
+    fn run_until_stderr_matches(&mut self, pattern: &regex::Regex) -> Result<()> {
+        if dry_run_skip(self) {
+            return Ok(());
+        }
+        self.stderr(Stdio::piped());
+        let span = subprocess_span(self);
+        let _guard = span.enter();
+        let start = Instant::now();
+        let mut child = spawn_logged(self)?;
+        let pid = Pid::from_child(&child);
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let mut tail = TailBuffer::new(MAX_STDERR_BYTES);
+        let timeout = default_timeout();
+        let (r, st) = wait_logged(&span, self, &mut child, start, timeout, || -> Result<_> {
+            let r = find_line(stderr, |line| {
+                tail.push(line.as_bytes());
+                tail.push(b"\n");
+                pattern.is_match(line)
+            });
+            if let Ok(Some(_)) = r {
+                stop_child(pid)?;
+            }
+            Ok(r)
+        })?;
+        let r = r?;
+        if let Ok(Some(line)) = &r {
+            anyhow::bail!("Subprocess stderr matched `{pattern}`: {line}");
+        }
+        let Some(st) = st else {
+            return Err(timed_out(timeout, tail.to_string_lossy()));
+        };
+        if !st.success() {
+            return Err(CommandError::new(st, tail.into_bytes())
+                .with_cmdline(self)
+                .into());
+        }
+        r.context("Reading stderr").map(drop)
+    }
+
+    fn run_and_parse_json<T: serde::de::DeserializeOwned>(&mut self) -> Result<T> {
+        ensure_not_dry_run()?;
+        let stdout = capture_tempfile()?;
+        self.stdout(stdout.try_clone()?);
+        // Some tools describe their failure on stdout, so include it in the error
+        self.run()
+            .map_err(|e| match e.downcast::<CommandError>() {
+                Ok(mut ce) => {
+                    ce.stdout = stdout
+                        .try_clone()
+                        .ok()
+                        .map(|f| last_utf8_content_from_file(f, MAX_STDERR_BYTES));
+                    ce.into()
+                }
+                Err(e) => e,
+            })
+            .map_err(annotate_terminated)?;
+        parse_json_from_file(&stdout, "stdout")
+    }
+
+    fn run_and_parse_json_with<T, F: FnOnce(&mut dyn Read) -> Result<T>>(
+        &mut self,
+        f: F,
+    ) -> Result<T> {
+        let stdout = run_capturing_stdout_file(self)?;
+        f(&mut std::io::BufReader::new(RetryInterrupted(stdout)))
+    }
+
+    fn run_and_parse_json_versioned<T: serde::de::DeserializeOwned>(
+        &mut self,
+        expected_version: &str,
+    ) -> Result<T> {
+        let v: serde_json::Value = self.run_and_parse_json()?;
+        let version = ["version", "schemaVersion"]
+            .into_iter()
+            .find_map(|k| v.get(k))
+            .ok_or_else(|| anyhow::anyhow!("Missing version field in JSON output"))?;
+        let version = match version {
+            serde_json::Value::String(s) => s.clone(),
+            o => o.to_string(),
+        };
+        if version != expected_version {
+            anyhow::bail!("Unexpected JSON output version {version}, expected {expected_version}");
+        }
+        serde_json::from_value(v).context("Parsing JSON from stdout")
+    }
+
+    fn run_and_parse_json_or_default<T: serde::de::DeserializeOwned + Default>(
+        &mut self,
+    ) -> Result<T> {
+        let mut stdout = run_capturing_stdout_file(self)?;
+        let mut buf = Vec::new();
+        stdout.read_to_end(&mut buf).context("Reading stdout")?;
+        if buf.iter().all(u8::is_ascii_whitespace) {
+            return Ok(T::default());
+        }
+        parse_json_from_file(&stdout, "stdout")
+    }
+
+    fn run_and_parse_yaml<T: serde::de::DeserializeOwned>(&mut self) -> Result<T> {
+        let stdout = run_capturing_stdout_file(self)?;
+        parse_yaml_from_file(&stdout, "stdout")
+    }
+
+    fn run_and_parse_json_from_stderr<T: serde::de::DeserializeOwned>(&mut self) -> Result<T> {
+        ensure_not_dry_run()?;
+        let stderr = capture_tempfile()?;
+        self.stderr(stderr.try_clone()?);
+        status_logged(self, Some(&stderr))?
+            .check_status(stderr.try_clone()?)
+            .map_err(|e| e.with_cmdline(self))?;
+        parse_json_from_file(&stderr, "stderr")
+    }
+
+    fn run_with_stdin(&mut self, input: &[u8]) -> Result<()> {
+        run_with_stdin_impl(self, input)
+    }
+
+    fn run_with_stdin_reader(&mut self, r: impl Read + Send + 'static) -> Result<()> {
+        run_with_stdin_impl(self, r)
+    }
+
+    fn run_and_parse_json_lines<T: serde::de::DeserializeOwned>(&mut self) -> Result<Vec<T>> {
+        let stdout = run_capturing_stdout_file(self)?;
+        let mut r = Vec::new();
+        for (i, line) in std::io::BufReader::new(stdout).lines().enumerate() {
+            let line = line.context("Reading stdout")?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let v = serde_json::from_str(&line)
+                .with_context(|| format!("Parsing JSON from stdout line {}", i + 1))?;
+            r.push(v);
+        }
+        Ok(r)
+    }
+
+    fn run_with_json_progress<P, R>(&mut self, mut on_progress: impl FnMut(P)) -> Result<R>
+    where
+        P: serde::de::DeserializeOwned,
+        R: serde::de::DeserializeOwned,
+    {
+        let mut err = None;
+        // The latest line and its number, which is a progress event if another follows
+        let mut last: Option<(usize, String)> = None;
+        let mut n = 0;
+        self.run_streaming_lines(|line| {
+            n += 1;
+            if err.is_some() || line.trim().is_empty() {
+                return;
+            }
+            let Some((i, prev)) = last.replace((n, line.to_owned())) else {
+                return;
+            };
+            match serde_json::from_str::<P>(&prev) {
+                Ok(p) => on_progress(p),
+                Err(e) => {
+                    let e = anyhow::Error::new(e);
+                    err = Some(e.context(format!("Parsing JSON from stdout line {i}")));
+                }
+            }
+        })?;
+        if let Some(e) = err {
+            return Err(e);
+        }
+        let (_, last) =
+            last.ok_or_else(|| anyhow::anyhow!("Parsing JSON from stdout: no output"))?;
+        serde_json::from_str(&last).context("Parsing final JSON result from stdout")
+    }
+
+    fn run_and_parse_json_stream<T: serde::de::DeserializeOwned>(&mut self) -> Result<Vec<T>> {
+        let stdout = run_capturing_stdout_file(self)?;
+        let mut stream =
+            serde_json::Deserializer::from_reader(std::io::BufReader::new(stdout)).into_iter();
+        let mut r = Vec::new();
+        loop {
+            let offset = stream.byte_offset();
+            match stream.next() {
+                Some(v) => r.push(
+                    v.with_context(|| format!("Parsing JSON from stdout at offset {offset}"))?,
+                ),
+                None => return Ok(r),
+            }
+        }
+    }
+
+    fn run_and_parse_json_bounded<T: serde::de::DeserializeOwned>(
+        &mut self,
+        max_bytes: usize,
+    ) -> Result<T> {
+        ensure_not_dry_run()?;
+        let stderr = capture_tempfile()?;
+        self.stderr(stderr.try_clone()?);
+        self.stdout(Stdio::piped());
+        let span = subprocess_span(self);
+        let _guard = span.enter();
+        let start = Instant::now();
+        let mut child = spawn_logged(self)?;
+        let pid = Pid::from_child(&child);
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let mut buf = Vec::new();
+        // Read one more byte than allowed, to detect excess output
+        let limit = u64::try_from(max_bytes)
+            .unwrap_or(u64::MAX)
+            .saturating_add(1);
+        let timeout = default_timeout();
+        let (r, st) = with_stderr_guard(&stderr, MAX_STDERR_BYTES, || {
+            wait_logged(&span, self, &mut child, start, timeout, || -> Result<_> {
+                let r = RetryInterrupted(stdout).take(limit).read_to_end(&mut buf);
+                if buf.len() > max_bytes {
+                    stop_child(pid)?;
+                }
+                Ok(r)
+            })
+        })?;
+        let r = r?;
+        if buf.len() > max_bytes {
+            anyhow::bail!("Subprocess output exceeded {max_bytes} bytes");
+        }
+        let Some(st) = st else {
+            let stderr = last_utf8_content_from_file(stderr, MAX_STDERR_BYTES);
+            return Err(timed_out(timeout, stderr));
+        };
+        st.check_status(stderr).map_err(|e| e.with_cmdline(self))?;
+        r.context("Reading stdout")?;
+        parse_json_from_file(std::io::Cursor::new(buf), "stdout")
+    }
+
+    fn run_and_parse_json_validated<T: serde::de::DeserializeOwned>(
+        &mut self,
+        attempts: u32,
+        valid: impl Fn(&T) -> bool,
+    ) -> Result<T> {
+        let mut attempt = 1;
+        loop {
+            let r = self.run_and_parse_json::<T>();
+            if attempt >= attempts {
+                if matches!(&r, Ok(v) if !valid(v)) {
+                    tracing::warn!("Output failed validation after {attempts} attempts");
+                }
+                return r;
+            }
+            match r {
+                Ok(v) if valid(&v) => return Ok(v),
+                Ok(_) => tracing::warn!(
+                    "Attempt {attempt}/{attempts} output failed validation, retrying"
+                ),
+                Err(e) => tracing::warn!("Attempt {attempt}/{attempts} failed, retrying: {e}"),
+            }
+            attempt += 1;
+        }
+    }
+
+    fn run_and_merge_json<T: serde::de::DeserializeOwned>(
+        &mut self,
+        into: &mut Vec<T>,
+    ) -> Result<()> {
+        use serde::de::DeserializeSeed;
+        let stdout = run_capturing_stdout_file(self)?;
+        let len = into.len();
+        let mut de = serde_json::Deserializer::from_reader(std::io::BufReader::new(
+            RetryInterrupted(stdout),
+        ));
+        ExtendVec(into)
+            .deserialize(&mut de)
+            .and_then(|()| de.end())
+            .map_err(|e| {
+                into.truncate(len);
+                anyhow::Error::new(e).context("Parsing JSON array from stdout")
+            })
+    }
+
+    fn run_peek_stdout(&mut self, n: usize) -> Result<Vec<u8>> {
+        ensure_not_dry_run()?;
+        let stderr = capture_tempfile()?;
+        self.stderr(stderr.try_clone()?);
+        self.stdout(Stdio::piped());
+        let span = subprocess_span(self);
+        let _guard = span.enter();
+        let start = Instant::now();
+        let mut child = spawn_logged(self)?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let mut buf = Vec::new();
+        let timeout = default_timeout();
+        let (r, st) = with_stderr_guard(&stderr, MAX_STDERR_BYTES, || {
+            // The pipe is closed once read, so that the child isn't blocked writing to it
+            wait_logged(&span, self, &mut child, start, timeout, || {
+                stdout.take(n as u64).read_to_end(&mut buf)
+            })
+        })?;
+        let Some(st) = st else {
+            let stderr = last_utf8_content_from_file(stderr, MAX_STDERR_BYTES);
+            return Err(timed_out(timeout, stderr));
+        };
+        if !(buf.len() == n && st.signal() == Some(libc::SIGPIPE)) {
+            st.check_status(stderr).map_err(|e| e.with_cmdline(self))?;
+        }
+        r.context("Reading stdout")?;
+        Ok(buf)
+    }
+
+    fn run_streaming_lines(&mut self, f: impl FnMut(&str)) -> Result<()> {
+        ensure_not_dry_run()?;
+        let stderr = capture_tempfile()?;
+        self.stderr(stderr.try_clone()?);
+        self.stdout(Stdio::piped());
+        let span = subprocess_span(self);
+        let _guard = span.enter();
+        let start = Instant::now();
+        let mut child = spawn_logged(self)?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let timeout = default_timeout();
+        let (r, st) = with_stderr_guard(&stderr, MAX_STDERR_BYTES, || {
+            // Note the pipe is closed once read, so even on error the child won't block
+            wait_logged(&span, self, &mut child, start, timeout, || {
+                for_each_line(stdout, f)
+            })
+        })?;
+        let Some(st) = st else {
+            let stderr = last_utf8_content_from_file(stderr, MAX_STDERR_BYTES);
+            return Err(timed_out(timeout, stderr));
+        };
+        st.check_status(stderr).map_err(|e| e.with_cmdline(self))?;
+        r.context("Reading stdout")
+    }
+
+    fn run_journaled(&mut self) -> Result<()> {
+        if dry_run_skip(self) {
+            return Ok(());
+        }
+        let identifier = Path::new(self.get_program())
+            .file_name()
+            .unwrap_or(self.get_program())
+            .to_string_lossy()
+            .into_owned();
+        let (output_r, output_w) = std::os::unix::net::UnixStream::pair()?;
+        self.stdout(std::os::fd::OwnedFd::from(output_w.try_clone()?));
+        self.stderr(std::os::fd::OwnedFd::from(output_w));
+        let span = subprocess_span(self);
+        let _guard = span.enter();
+        let start = Instant::now();
+        let r = spawn_logged(self);
+        // Drop the write ends held by the Command, so that we see EOF when the child exits
+        self.stdout(Stdio::null()).stderr(Stdio::null());
+        let mut child = r?;
+        let mut tail = TailBuffer::new(MAX_STDERR_BYTES);
+        let timeout = default_timeout();
+        let (r, st) = wait_logged(&span, self, &mut child, start, timeout, || {
+            for_each_line(output_r, |line| {
+                tail.push(line.as_bytes());
+                tail.push(b"\n");
+                crate::journal::journal_send(
+                    libsystemd::logging::Priority::Info,
+                    line,
+                    [("SYSLOG_IDENTIFIER", identifier.as_str())].into_iter(),
+                );
+            })
+        })?;
+        let Some(st) = st else {
+            return Err(timed_out(timeout, tail.to_string_lossy()));
+        };
+        if !st.success() {
+            return Err(CommandError::new(st, tail.into_bytes())
+                .with_cmdline(self)
+                .into());
+        }
+        r.context("Reading output")
+    }
+
+    #[allow(unsafe_code)]
+    fn run_with_pty(&mut self) -> Result<String> {
+        use rustix::pty::OpenptFlags;
+        ensure_not_dry_run()?;
+        let master =
+            rustix::pty::openpt(OpenptFlags::RDWR | OpenptFlags::NOCTTY | OpenptFlags::CLOEXEC)
+                .context("Allocating pseudo-terminal")?;
+        rustix::pty::grantpt(&master).context("grantpt")?;
+        rustix::pty::unlockpt(&master).context("unlockpt")?;
+        let name = rustix::pty::ptsname(&master, Vec::new()).context("ptsname")?;
+        let pts = rustix::fs::open(
+            name.as_c_str(),
+            rustix::fs::OFlags::RDWR | rustix::fs::OFlags::NOCTTY | rustix::fs::OFlags::CLOEXEC,
+            rustix::fs::Mode::empty(),
+        )
+        .with_context(|| format!("Opening {name:?}"))?;
+        self.stdin(pts.try_clone()?);
+        self.stdout(pts.try_clone()?);
+        self.stderr(pts);
+        // SAFETY: setsid() and ioctl() are async-signal-safe
+        unsafe {
+            self.pre_exec(|| {
+                rustix::process::setsid()?;
+                // At this point the terminal has been set up as stdin
+                let stdin = std::os::fd::BorrowedFd::borrow_raw(0);
+                rustix::process::ioctl_tiocsctty(stdin)?;
+                Ok(())
+            });
+        }
+        let span = subprocess_span(self);
+        let _guard = span.enter();
+        let start = Instant::now();
+        let r = spawn_logged(self);
+        // Drop the terminal fds held by the Command, so that reads fail when the child exits
+        self.stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        let mut child = r?;
+        let mut master = File::from(master);
+        let mut output = Vec::new();
+        let mut buf = [0u8; 8192];
+        let timeout = default_timeout();
+        let (r, st) = wait_logged(&span, self, &mut child, start, timeout, || loop {
+            match retry_interrupted(|| master.read(&mut buf)) {
+                Ok(0) => break Ok(()),
+                Ok(n) => output.extend_from_slice(&buf[..n]),
+                // This is how Linux reports that all terminal fds were closed
+                Err(e) if e.raw_os_error() == Some(libc::EIO) => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        })?;
+        let tail = || {
+            let mut tail = TailBuffer::new(MAX_STDERR_BYTES);
+            tail.push(&output);
+            tail
+        };
+        let Some(st) = st else {
+            return Err(timed_out(timeout, tail().to_string_lossy()));
+        };
+        if !st.success() {
+            return Err(CommandError::new(st, tail().into_bytes())
+                .with_cmdline(self)
+                .into());
+        }
+        r.context("Reading output")?;
+        Ok(String::from_utf8_lossy(&output).into_owned())
+    }
+
+    fn ensure_exists(&self) -> Result<PathBuf> {
+        let program = Path::new(self.get_program());
+        let is_executable =
+            |p: &Path| p.is_file() && rustix::fs::access(p, rustix::fs::Access::EXEC_OK).is_ok();
+        if program.as_os_str().as_encoded_bytes().contains(&b'/') {
+            let path = match self.get_current_dir() {
+                Some(dir) => dir.join(program),
+                None => program.to_owned(),
+            };
+            return if is_executable(&path) {
+                Ok(path)
+            } else {
+                Err(anyhow::anyhow!("program `{}` not found", path.display()))
+            };
+        }
+        let search_path = match self.get_envs().find(|(k, _)| *k == "PATH") {
+            Some((_, v)) => v.map(ToOwned::to_owned),
+            None => std::env::var_os("PATH"),
+        }
+        .unwrap_or_default();
+        std::env::split_paths(&search_path)
+            .map(|dir| dir.join(program))
+            .find(|p| is_executable(p))
+            .ok_or_else(|| anyhow::anyhow!("program `{}` not found in PATH", program.display()))
+    }
+
+    fn warn_on_shell_metachars(&self) -> bool {
+        const METACHARS: &[u8] = b"$|;`";
+        let suspicious = self
+            .get_args()
+            .position(|arg| arg.as_encoded_bytes().iter().any(|b| METACHARS.contains(b)));
+        let Some(i) = suspicious else {
+            return false;
+        };
+        // Identify the argument by position, as only the command line is redacted
+        tracing::warn!(
+            "Argument {} contains shell metacharacters, but will not be interpreted by a shell: {}",
+            i + 1,
+            redacted_cmdline(self, &[])
+        );
+        true
+    }
+
+    fn run_and_capture_stdout(&mut self) -> Result<String> {
+        let mut stdout = run_capturing_stdout_file(self)?;
+        let mut buf = Vec::new();
+        stdout.read_to_end(&mut buf).context("Reading stdout")?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+
+    fn run_and_capture_stdout_strict(&mut self) -> Result<String> {
+        let mut stdout = run_capturing_stdout_file(self)?;
+        let mut buf = Vec::new();
+        stdout.read_to_end(&mut buf).context("Reading stdout")?;
+        String::from_utf8(buf).map_err(|e| {
+            let offset = e.utf8_error().valid_up_to();
+            anyhow::anyhow!("Invalid UTF-8 in stdout at byte offset {offset}")
+        })
+    }
+
+    fn run_expecting_stdout(&mut self, expected: &str) -> Result<()> {
+        let stdout = self.run_and_capture_stdout()?;
+        let (expected, actual) = (expected.trim(), stdout.trim());
+        if expected != actual {
+            let expected: Vec<_> = expected.lines().collect();
+            let actual: Vec<_> = actual.lines().collect();
+            anyhow::bail!(
+                "Unexpected stdout (- expected, + actual):\n{}",
+                diff_lines(&expected, &actual)
+            );
+        }
+        Ok(())
+    }
+
+    fn run_and_parse_env(&mut self) -> Result<HashMap<String, String>> {
+        parse_env(&self.run_and_capture_stdout()?).context("Parsing stdout")
+    }
+
+    fn run_and_parse_paths(&mut self, nul_separated: bool) -> Result<Vec<PathBuf>> {
+        use std::os::unix::ffi::OsStrExt;
+        let mut stdout = run_capturing_stdout_file(self)?;
+        let mut buf = Vec::new();
+        stdout.read_to_end(&mut buf).context("Reading stdout")?;
+        let sep = if nul_separated { b'\0' } else { b'\n' };
+        Ok(buf
+            .split(|&b| b == sep)
+            .filter(|p| !p.is_empty())
+            .map(|p| PathBuf::from(std::ffi::OsStr::from_bytes(p)))
+            .collect())
+    }
+}
+
+/// Composable options for executing a command, as an alternative to
+/// the variants of [`CommandRunExt::run`] which each apply one option.
+#[derive(Debug)]
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) struct RunConfig {
+    timeout: Option<Duration>,
+    stderr_limit: usize,
+    quiet_stdout: bool,
+    clean_env: Option<Vec<String>>,
+    stderr_line_numbers: bool,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            timeout: None,
+            stderr_limit: MAX_STDERR_BYTES,
+            quiet_stdout: false,
+            clean_env: None,
+            stderr_line_numbers: false,
+        }
+    }
+}
+
+#[allow(dead_code)] // Not used outside tests yet
+impl RunConfig {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// See [`CommandRunExt::run_with_timeout`]. This overrides the default timeout
+    /// from `BOOTC_SUBPROC_TIMEOUT`.
+    pub(crate) fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// See [`CommandRunExt::run_with_stderr_limit`].
+    pub(crate) fn stderr_limit(mut self, limit: usize) -> Self {
+        self.stderr_limit = limit;
+        self
+    }
+
+    /// See [`CommandRunExt::run_quiet`].
+    pub(crate) fn quiet_stdout(mut self, quiet: bool) -> Self {
+        self.quiet_stdout = quiet;
+        self
+    }
+
+    /// See [`CommandRunExt::run_clean_env`].
+    pub(crate) fn clean_env(mut self, keep: &[&str]) -> Self {
+        self.clean_env = Some(keep.iter().map(|&k| k.to_owned()).collect());
+        self
+    }
+
+    /// If the child fails, prefix each line of the stderr included in the error with
+    /// its line number (counting from the start of stderr, not the retained tail).
+    pub(crate) fn stderr_line_numbers(mut self, enabled: bool) -> Self {
+        self.stderr_line_numbers = enabled;
+        self
+    }
+
+    /// Apply the options to the command and execute it, returning an error if
+    /// the child exited unsuccessfully.
+    pub(crate) fn run(&self, cmd: &mut Command) -> Result<()> {
+        if self.quiet_stdout {
+            cmd.stdout(null_sink()?);
+        }
+        if let Some(keep) = self.clean_env.as_ref() {
+            let keep: Vec<_> = keep.iter().map(String::as_str).collect();
+            scrub_env(cmd, &keep);
+        }
+        run_impl(
+            cmd,
+            self.timeout.or_else(default_timeout),
+            self.stderr_limit,
+            self.stderr_line_numbers,
+            None,
+        )
+    }
+}
+
+/// An abstraction over executing commands, so that code doing so can be
+/// tested deterministically (see `MockCommandRunner`) without spawning processes.
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) trait CommandRunner {
+    /// See [`CommandRunExt::run`].
+    fn run(&self, cmd: &mut Command) -> Result<()>;
+
+    /// See [`CommandRunExt::run_and_capture_stdout`].
+    fn run_and_capture_stdout(&self, cmd: &mut Command) -> Result<String>;
+
+    /// See [`CommandRunExt::run_and_parse_json`].
+    fn run_and_parse_json<T: serde::de::DeserializeOwned>(&self, cmd: &mut Command) -> Result<T>
+    where
+        Self: Sized,
+    {
+        let stdout = self.run_and_capture_stdout(cmd)?;
+        serde_json::from_str(&stdout).with_context(|| {
+            let snippet: String = stdout.chars().take(1024).collect();
+            format!("Parsing JSON from stdout: {snippet}")
+        })
+    }
+}
+
+/// The [`CommandRunner`] which actually executes commands.
+#[derive(Debug, Default)]
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) struct HostCommandRunner;
+
+impl CommandRunner for HostCommandRunner {
+    fn run(&self, cmd: &mut Command) -> Result<()> {
+        cmd.run()
+    }
+
+    fn run_and_capture_stdout(&self, cmd: &mut Command) -> Result<String> {
+        cmd.run_and_capture_stdout()
+    }
+
+    fn run_and_parse_json<T: serde::de::DeserializeOwned>(&self, cmd: &mut Command) -> Result<T> {
+        cmd.run_and_parse_json()
+    }
+}
+
+/// A [`CommandRunner`] for unit tests, which returns canned results for
+/// registered command lines instead of executing anything.
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub(crate) struct MockCommandRunner {
+    /// Maps the program and arguments to the exit code and stdout
+    expected: std::collections::HashMap<Vec<String>, (i32, String)>,
+}
+
+#[cfg(test)]
+impl MockCommandRunner {
+    /// When a command with exactly this program and arguments is run, it
+    /// "exits" with the provided code, having written `stdout`.
+    pub(crate) fn expect(&mut self, argv: &[&str], code: i32, stdout: &str) -> &mut Self {
+        let argv = argv.iter().map(|&s| s.to_owned()).collect();
+        self.expected.insert(argv, (code, stdout.to_owned()));
+        self
+    }
+
+    fn lookup(&self, cmd: &Command) -> Result<String> {
+        let argv: Vec<String> = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|s| s.to_string_lossy().into_owned())
+            .collect();
+        let Some((code, stdout)) = self.expected.get(&argv) else {
+            anyhow::bail!("Unexpected command: {argv:?}");
+        };
+        if *code != 0 {
+            let status = ExitStatus::from_raw(code << 8);
+            return Err(CommandError::new(status, Vec::new()).into());
+        }
+        Ok(stdout.clone())
+    }
+}
+
+#[cfg(test)]
+impl CommandRunner for MockCommandRunner {
+    fn run(&self, cmd: &mut Command) -> Result<()> {
+        self.lookup(cmd).map(drop)
+    }
+
+    fn run_and_capture_stdout(&self, cmd: &mut Command) -> Result<String> {
+        self.lookup(cmd)
+    }
+}
+
+/// A sequence of commands, where the stdout of each stage is connected
+/// to the stdin of the next.
+#[derive(Debug)]
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) struct CommandPipeline {
+    stages: Vec<Command>,
+}
+
+#[allow(dead_code)] // Not used outside tests yet
+impl CommandPipeline {
+    /// Create a pipeline from the provided stages, in order.
+    pub(crate) fn new(stages: impl IntoIterator<Item = Command>) -> Self {
+        Self {
+            stages: stages.into_iter().collect(),
+        }
+    }
+
+    /// Execute all stages, returning the stdout of the final stage (with invalid UTF-8
+    /// replaced lossily). If any stage exits unsuccessfully an error is returned
+    /// identifying the first such stage, including its captured stderr; stages other
+    /// than the last being killed by `SIGPIPE` (as with `yes | head -n1`) is not a failure.
+    pub(crate) fn run(&mut self) -> Result<String> {
+        self.run_impl(None)
+    }
+
+    /// Like [`CommandPipeline::run`], but the stages are executed in a new process group,
+    /// and if they have not all exited by `deadline`, the group is sent `SIGTERM`, and then
+    /// `SIGKILL` after a short grace period. In that case the error names the first stage
+    /// which was still running, and can be downcast to [`CommandTimedOut`].
+    pub(crate) fn run_with_deadline(&mut self, deadline: Instant) -> Result<String> {
+        self.run_impl(Some(deadline))
+    }
+
+    fn run_impl(&mut self, deadline: Option<Instant>) -> Result<String> {
+        ensure_not_dry_run()?;
+        let start = Instant::now();
+        let n = self.stages.len();
+        anyhow::ensure!(n > 0, "Empty pipeline");
+        let mut stdout = capture_tempfile()?;
+        let mut children: Vec<(Child, File)> = Vec::with_capacity(n);
+        let mut prev_stdout = None;
+        let mut pgid = None;
+        for (i, cmd) in self.stages.iter_mut().enumerate() {
+            let stderr = capture_tempfile()?;
+            cmd.stderr(stderr.try_clone()?);
+            if let Some(prev) = prev_stdout.take() {
+                cmd.stdin(Stdio::from(prev));
+            }
+            if i + 1 < n {
+                cmd.stdout(Stdio::piped());
+            } else {
+                cmd.stdout(stdout.try_clone()?);
+            }
+            if deadline.is_some() {
+                // The first stage creates the group, which the others then join
+                cmd.process_group(pgid.map_or(0, |pgid: Pid| pgid.as_raw_nonzero().get()));
+            }
+            let r = spawn_logged(cmd);
+            // Drop our copy of the read end of the previous stage's stdout, so that
+            // it sees EPIPE if this stage exits early
+            cmd.stdin(Stdio::null());
+            match r {
+                Ok(mut child) => {
+                    prev_stdout = child.stdout.take();
+                    if deadline.is_some() && pgid.is_none() {
+                        pgid = Some(Pid::from_child(&child));
+                    }
+                    children.push((child, stderr));
+                }
+                Err(e) => {
+                    kill_stages(&mut children);
+                    return Err(e.context(format!("Spawning pipeline stage {i}")));
+                }
+            }
+        }
+        let mut statuses = Vec::with_capacity(n);
+        for (child, _) in children.iter_mut() {
+            let r = match deadline {
+                Some(deadline) => {
+                    wait_timeout(child, deadline.saturating_duration_since(Instant::now()))
+                }
+                None => child.wait().map(Some).map_err(Into::into),
+            };
+            let st = match r {
+                Ok(Some(st)) => st,
+                // If the deadline passed, the remaining children are terminated below
+                Ok(None) => break,
+                Err(e) => {
+                    let i = statuses.len();
+                    kill_stages(&mut children[i..]);
+                    return Err(e.context(format!("Waiting for pipeline stage {i}")));
+                }
+            };
+            tracing::trace!("exited: {st:?}");
+            statuses.push(st);
+        }
+        if let (Some(deadline), Some(pgid)) = (deadline, pgid) {
+            let i = statuses.len();
+            if i < n {
+                terminate_process_group(pgid, &mut children[i..])?;
+                let program = self.stages[i].get_program().to_string_lossy();
+                let e = CommandTimedOut {
+                    after: deadline.saturating_duration_since(start),
+                    stderr: last_utf8_content_from_file(
+                        children.swap_remove(i).1,
+                        MAX_STDERR_BYTES,
+                    ),
+                };
+                return Err(anyhow::Error::new(e).context(format!(
+                    "Pipeline deadline exceeded while stage {i} ({program}) was running"
+                )));
+            }
+        }
+        for (i, (st, (_, stderr))) in statuses.into_iter().zip(children).enumerate() {
+            // As in a shell, an earlier stage is expected to be killed by SIGPIPE if
+            // a later one exits without reading all of its input
+            if i + 1 < n && st.signal() == Some(libc::SIGPIPE) {
+                continue;
+            }
+            let program = self.stages[i].get_program().to_string_lossy();
+            st.check_status(stderr)
+                .map_err(|e| e.with_cmdline(&self.stages[i]))
+                .with_context(|| format!("Pipeline stage {i} ({program}) failed"))?;
+        }
+        stdout.seek(std::io::SeekFrom::Start(0)).context("seek")?;
+        let mut buf = Vec::new();
+        stdout.read_to_end(&mut buf).context("Reading stdout")?;
+        Ok(String::from_utf8_lossy(&buf).into_owned())
+    }
+}
+
+/// Kill and reap the provided pipeline stages, ignoring errors.
+fn kill_stages(children: &mut [(Child, File)]) {
+    for (child, _) in children {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// Helpers intended for [`tokio::process::Command`].
+///
+/// These set [`tokio::process::Command::kill_on_drop`], so if the returned
+/// future is dropped before completion (e.g. because it lost a `select!` race)
+/// the child is killed with `SIGKILL`, rather than continuing to run in the background.
+#[allow(async_fn_in_trait)]
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) trait AsyncCommandRunExt {
+    /// Asynchronously execute the child, and return an error if the child exited unsuccessfully.
+    async fn run(&mut self) -> Result<()>;
+
+    /// Asynchronously execute the child process, parsing its stdout as JSON.
+    /// Note that reading back the captured stdout is done synchronously, as it
+    /// is a local tempfile and hence expected to be fast.
+    async fn run_and_parse_json<T: serde::de::DeserializeOwned>(&mut self) -> Result<T>;
+
+    /// Like [`AsyncCommandRunExt::run`], but if `token` is cancelled before the child
+    /// exits, it is sent `SIGTERM`, and then `SIGKILL` after a short grace period.
+    /// In that case the returned error can be downcast to [`CommandCancelled`].
+    async fn run_cancellable(&mut self, token: CancellationToken) -> Result<()>;
+
+    /// Like [`AsyncCommandRunExt::run`], but if the child has not exited after `timeout`,
+    /// it is sent `SIGTERM`, and then `SIGKILL` after a short grace period, as with
+    /// [`CommandRunExt::run_with_timeout`]. In that case the returned error can be
+    /// downcast to [`CommandTimedOut`].
+    async fn run_with_timeout(&mut self, timeout: Duration) -> Result<()>;
+
+    /// Like [`AsyncCommandRunExt::run`], but if the child has not exited after `timeout`,
+    /// return [`DeadlineOutcome::Running`] rather than killing it, so that the caller can
+    /// decide whether to keep waiting or terminate it.
+    async fn run_with_deadline(&mut self, timeout: Duration) -> Result<DeadlineOutcome>;
+
+    /// Spawn the child, returning a stream of chunks of its stdout as they arrive.
+    /// Once stdout is closed, the exit status of the child is checked; if it was
+    /// unsuccessful the final item is the error (with its trailing stderr).
+    fn run_stream(&mut self) -> Result<CommandStream>;
+}
+
+/// The stdout of a child, returned by [`AsyncCommandRunExt::run_stream`].
+/// Dropping this kills the child.
+pub(crate) struct CommandStream {
+    stdout: Option<tokio_util::io::ReaderStream<tokio::process::ChildStdout>>,
+    exited: Option<std::pin::Pin<Box<dyn Future<Output = Result<()>> + Send>>>,
+}
+
+impl std::fmt::Debug for CommandStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CommandStream").finish_non_exhaustive()
+    }
+}
+
+impl futures_core::Stream for CommandStream {
+    type Item = Result<bytes::Bytes>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        use std::task::Poll;
+        if let Some(stdout) = self.stdout.as_mut() {
+            match std::pin::Pin::new(stdout).poll_next(cx) {
+                Poll::Ready(Some(r)) => return Poll::Ready(Some(r.context("Reading stdout"))),
+                Poll::Ready(None) => self.stdout = None,
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let Some(exited) = self.exited.as_mut() else {
+            return Poll::Ready(None);
+        };
+        let r = std::task::ready!(exited.as_mut().poll(cx));
+        self.exited = None;
+        Poll::Ready(r.err().map(Err))
+    }
+}
+
+/// The result of [`AsyncCommandRunExt::run_with_deadline`].
+#[derive(Debug)]
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) enum DeadlineOutcome {
+    /// The child exited successfully
+    Exited,
+    /// The child is still running
+    Running(RunningCommand),
+}
+
+/// A child which was still running when its deadline passed.
+#[derive(Debug)]
+pub(crate) struct RunningCommand {
+    child: tokio::process::Child,
+    stderr: File,
+    command: Option<String>,
+}
+
+#[allow(dead_code)] // Not used outside tests yet
+impl RunningCommand {
+    /// Wait for the child to exit, or until `timeout` has passed again.
+    pub(crate) async fn wait_with_deadline(mut self, timeout: Duration) -> Result<DeadlineOutcome> {
+        let wait = wait_async_registered(&mut self.child);
+        let Ok(st) = tokio::time::timeout(timeout, wait).await else {
+            return Ok(DeadlineOutcome::Running(self));
+        };
+        let st = st?;
+        tracing::trace!("exited: {st:?}");
+        self.check_status(st)?;
+        Ok(DeadlineOutcome::Exited)
+    }
+
+    /// Wait for the child to exit, and return an error if it exited unsuccessfully.
+    pub(crate) async fn wait(mut self) -> Result<()> {
+        let st = wait_async_registered(&mut self.child).await?;
+        tracing::trace!("exited: {st:?}");
+        self.check_status(st)
+    }
+
+    /// Check the exit status; an error includes the command line recorded when the
+    /// child was spawned (see [`set_verbose_errors`]).
+    fn check_status(self, st: ExitStatus) -> Result<()> {
+        st.check_status(self.stderr).map_err(|mut e| {
+            e.command = self.command;
+            e
+        })?;
+        Ok(())
+    }
+
+    /// Send the child `SIGTERM`, and then `SIGKILL` after a short grace period.
+    pub(crate) async fn terminate(mut self) -> Result<ExitStatus> {
+        let st = terminate_async_child(&mut self.child).await?;
+        tracing::trace!("terminated: {st:?}");
+        Ok(st)
+    }
+}
+
+/// Async version of [`wait_logged`] without a span: wait for the child to exit while
+/// registered for [`shutdown_all`], only reaping it once deregistered. This waits
+/// via a pidfd, which becomes readable when the child exits; if it cannot be opened
+/// (e.g. on an old kernel), the child is not registered.
+async fn wait_async_registered(child: &mut tokio::process::Child) -> Result<ExitStatus> {
+    if let Some(pid) = child.id() {
+        let pidfd = Pid::from_raw(pid as i32)
+            .map(|pid| rustix::process::pidfd_open(pid, rustix::process::PidfdFlags::empty()));
+        match pidfd {
+            Some(Ok(pidfd)) => {
+                let _registration = ChildRegistration::new(pid);
+                let pidfd =
+                    tokio::io::unix::AsyncFd::with_interest(pidfd, tokio::io::Interest::READABLE)?;
+                drop(pidfd.readable().await?);
+            }
+            Some(Err(e)) => tracing::debug!("failed to open pidfd: {e}"),
+            None => {}
+        }
+    }
+    Ok(child.wait().await?)
+}
+
+/// Async version of [`stop_child`], which also reaps the child.
+async fn terminate_async_child(child: &mut tokio::process::Child) -> Result<ExitStatus> {
+    if let Some(pid) = child.id().and_then(|pid| Pid::from_raw(pid as i32)) {
+        if let Err(e) = rustix::process::kill_process(pid, Signal::Term) {
+            tracing::debug!("failed to send SIGTERM: {e}");
+        }
+        if let Ok(st) = tokio::time::timeout(TIMEOUT_GRACE_PERIOD, child.wait()).await {
+            return Ok(st?);
+        }
+    }
+    // This uses SIGKILL
+    child.kill().await.context("Killing child")?;
+    child.wait().await.context("Waiting for child")
+}
+
+impl AsyncCommandRunExt for tokio::process::Command {
+    async fn run(&mut self) -> Result<()> {
+        let stderr = capture_tempfile()?;
+        self.stderr(stderr.try_clone()?);
+        self.kill_on_drop(true);
+        pre_spawn(self.as_std())?;
+        let mut child = self.spawn()?;
+        let st = wait_async_registered(&mut child).await?;
+        tracing::trace!("exited: {st:?}");
+        Ok(st
+            .check_status(stderr)
+            .map_err(|e| e.with_cmdline(self.as_std()))?)
+    }
+
+    async fn run_and_parse_json<T: serde::de::DeserializeOwned>(&mut self) -> Result<T> {
+        let stdout = capture_tempfile()?;
+        self.stdout(stdout.try_clone()?);
+        self.run().await.map_err(annotate_terminated)?;
+        parse_json_from_file(&stdout, "stdout")
+    }
+
+    async fn run_cancellable(&mut self, token: CancellationToken) -> Result<()> {
+        let stderr = capture_tempfile()?;
+        self.stderr(stderr.try_clone()?);
+        self.kill_on_drop(true);
+        pre_spawn(self.as_std())?;
+        let mut child = self.spawn()?;
+        let st = {
+            let wait = wait_async_registered(&mut child);
+            tokio::pin!(wait);
+            let cancelled = token.cancelled();
+            tokio::pin!(cancelled);
+            std::future::poll_fn(|cx| {
+                if let std::task::Poll::Ready(r) = wait.as_mut().poll(cx) {
+                    return std::task::Poll::Ready(Some(r));
+                }
+                cancelled.as_mut().poll(cx).map(|()| None)
+            })
+            .await
+        };
+        if let Some(st) = st {
+            let st = st?;
+            tracing::trace!("exited: {st:?}");
+            return Ok(st
+                .check_status(stderr)
+                .map_err(|e| e.with_cmdline(self.as_std()))?);
+        }
+        let st = terminate_async_child(&mut child).await?;
+        tracing::trace!("cancelled: {st:?}");
+        Err(CommandCancelled {
+            stderr: last_utf8_content_from_file(stderr, MAX_STDERR_BYTES),
+        }
+        .into())
+    }
+
+    async fn run_with_timeout(&mut self, timeout: Duration) -> Result<()> {
+        let stderr = capture_tempfile()?;
+        self.stderr(stderr.try_clone()?);
+        self.kill_on_drop(true);
+        pre_spawn(self.as_std())?;
+        let mut child = self.spawn()?;
+        if let Ok(st) = tokio::time::timeout(timeout, wait_async_registered(&mut child)).await {
+            let st = st?;
+            tracing::trace!("exited: {st:?}");
+            return Ok(st
+                .check_status(stderr)
+                .map_err(|e| e.with_cmdline(self.as_std()))?);
+        }
+        let st = terminate_async_child(&mut child).await?;
+        tracing::trace!("timed out: {st:?}");
+        Err(CommandTimedOut {
+            after: timeout,
+            stderr: last_utf8_content_from_file(stderr, MAX_STDERR_BYTES),
+        }
+        .into())
+    }
+
+    async fn run_with_deadline(&mut self, timeout: Duration) -> Result<DeadlineOutcome> {
+        let stderr = capture_tempfile()?;
+        self.stderr(stderr.try_clone()?);
+        self.kill_on_drop(true);
+        pre_spawn(self.as_std())?;
+        let child = self.spawn()?;
+        let command = verbose_errors().then(|| redacted_cmdline(self.as_std(), &[]));
+        RunningCommand {
+            child,
+            stderr,
+            command,
+        }
+        .wait_with_deadline(timeout)
+        .await
+    }
+
+    fn run_stream(&mut self) -> Result<CommandStream> {
+        let stderr = capture_tempfile()?;
+        self.stderr(stderr.try_clone()?);
+        self.stdout(Stdio::piped());
+        self.kill_on_drop(true);
+        pre_spawn(self.as_std())?;
+        let mut child = self.spawn()?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let command = verbose_errors().then(|| redacted_cmdline(self.as_std(), &[]));
+        let exited = async move {
+            let st = wait_async_registered(&mut child).await?;
+            tracing::trace!("exited: {st:?}");
+            Ok(st.check_status(stderr).map_err(|mut e| {
+                e.command = command;
+                e
+            })?)
+        };
+        Ok(CommandStream {
+            stdout: Some(tokio_util::io::ReaderStream::new(stdout)),
+            exited: Some(Box::pin(exited)),
+        })
+    }
+}
+
+/// Execute the provided commands via [`AsyncCommandRunExt::run`], with at most
+/// `max_concurrent` running at a time. The results are returned in the same order
+/// as the commands.
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) async fn run_batch(
+    commands: Vec<tokio::process::Command>,
+    max_concurrent: usize,
+) -> Vec<Result<()>> {
+    async fn run_one(mut cmd: tokio::process::Command) -> Result<()> {
+        cmd.run().await
+    }
+    run_batch_with(commands, max_concurrent, run_one).await
+}
+
+/// Like [`run_batch`], but execute the commands via [`AsyncCommandRunExt::run_and_parse_json`].
+#[allow(dead_code)] // Not used outside tests yet
+pub(crate) async fn run_batch_parse_json<T: serde::de::DeserializeOwned + Send + 'static>(
+    commands: Vec<tokio::process::Command>,
+    max_concurrent: usize,
+) -> Vec<Result<T>> {
+    async fn run_one<T: serde::de::DeserializeOwned>(
+        mut cmd: tokio::process::Command,
+    ) -> Result<T> {
+        cmd.run_and_parse_json().await
+    }
+    run_batch_with(commands, max_concurrent, run_one::<T>).await
+}
+
+/// The implementation of [`run_batch`] and its variants, executing each command via `f`.
+async fn run_batch_with<T, F, Fut>(
+    commands: Vec<tokio::process::Command>,
+    max_concurrent: usize,
+    f: F,
+) -> Vec<Result<T>>
+where
+    T: Send + 'static,
+    F: Fn(tokio::process::Command) -> Fut + Copy + Send + 'static,
+    Fut: Future<Output = Result<T>> + Send,
+{
+    let n = commands.len();
+    let semaphore = std::sync::Arc::new(tokio::sync::Semaphore::new(max_concurrent.max(1)));
+    let mut tasks = tokio::task::JoinSet::new();
+    for (i, cmd) in commands.into_iter().enumerate() {
+        let semaphore = semaphore.clone();
+        tasks.spawn(async move {
+            let _permit = semaphore
+                .acquire()
+                .await
+                .expect("semaphore is never closed");
+            (i, f(cmd).await)
+        });
+    }
+    let mut results: Vec<Option<Result<T>>> = std::iter::repeat_with(|| None).take(n).collect();
+    while let Some(r) = tasks.join_next().await {
+        match r {
+            Ok((i, r)) => results[i] = Some(r),
+            Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+            // We never abort the tasks
+            Err(e) => unreachable!("{e}"),
+        }
+    }
+    results
+        .into_iter()
+        .map(|r| r.expect("task has completed"))
+        .collect()
+}
+
+#[test]
+fn command_run_ext() {
+    // The basics
+    Command::new("true").run().unwrap();
+    assert!(Command::new("false").run().is_err());
+
+    // Verify we capture stderr
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo expected-this-oops-message 1>&2; exit 1"])
+        .run()
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Subprocess failed: ExitStatus(unix_wait_status(256))\nexpected-this-oops-message\n"
+    );
+    let e = e.downcast_ref::<CommandError>().unwrap();
+    assert_eq!(e.code(), Some(1));
+    assert!(!e.signalled());
+    assert_eq!(e.stderr, "expected-this-oops-message\n");
+
+    // Ignoring invalid UTF-8
+    let e = Command::new("/bin/sh")
+        .args([
+            "-c",
+            r"printf 'expected\365\200\200\200\200-foo\300bar\300\300\n' 1>&2; exit 1",
+        ])
+        .run()
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Subprocess failed: ExitStatus(unix_wait_status(256))\nexpected�����-foo�bar��\n"
+    );
+    let e = e.downcast_ref::<CommandError>().unwrap();
+    assert_eq!(
+        e.stderr_bytes(),
+        b"expected\xf5\x80\x80\x80\x80-foo\xc0bar\xc0\xc0\n"
+    );
+}
+
+#[test]
+fn test_running_children() {
+    let td = tempfile::tempdir().unwrap();
+    let pidfile = td.path().join("pid");
+    let is_registered = |pid: i32| {
+        let children = RUNNING_CHILDREN.lock().unwrap();
+        children.contains(&pid)
+    };
+    std::thread::scope(|s| {
+        let h = s.spawn(|| {
+            Command::new("/bin/sh")
+                .args([
+                    "-c",
+                    r#"echo $$ > "$0.tmp"; mv "$0.tmp" "$0"; exec sleep 60"#,
+                ])
+                .arg(&pidfile)
+                .run()
+        });
+        let pid = loop {
+            if let Ok(pid) = std::fs::read_to_string(&pidfile) {
+                break pid.trim().parse::<i32>().unwrap();
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+        assert!(is_registered(pid));
+        // Don't use shutdown_all() here, as that would affect other tests
+        rustix::process::kill_process(Pid::from_raw(pid).unwrap(), Signal::Term).unwrap();
+        let e = h.join().unwrap().err().unwrap();
+        let e = e.downcast_ref::<CommandError>().unwrap();
+        assert_eq!(e.status.signal(), Some(libc::SIGTERM));
+        assert!(!is_registered(pid));
+    });
+}
+
+#[test]
+fn test_running_children_async() {
+    use tokio::process::Command as AsyncCommand;
+    let td = tempfile::tempdir().unwrap();
+    let pidfile = td.path().join("pid");
+    let is_registered = |pid: i32| {
+        let children = RUNNING_CHILDREN.lock().unwrap();
+        children.contains(&pid)
+    };
+    crate::testutil::block_on(async {
+        let mut cmd = AsyncCommand::new("/bin/sh");
+        cmd.args([
+            "-c",
+            r#"echo $$ > "$0.tmp"; mv "$0.tmp" "$0"; exec sleep 60"#,
+        ])
+        .arg(&pidfile);
+        let h = tokio::spawn(async move { cmd.run().await });
+        let pid = loop {
+            if let Ok(pid) = std::fs::read_to_string(&pidfile) {
+                break pid.trim().parse::<i32>().unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        assert!(is_registered(pid));
+        rustix::process::kill_process(Pid::from_raw(pid).unwrap(), Signal::Term).unwrap();
+        let e = h.await.unwrap().err().unwrap();
+        let e = e.downcast_ref::<CommandError>().unwrap();
+        assert_eq!(e.status.signal(), Some(libc::SIGTERM));
+        assert!(!is_registered(pid));
+    });
+}
+
+#[test]
+fn command_run_ext_quiet() {
+    Command::new("echo").arg("noise").run_quiet().unwrap();
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo noise; echo oops 1>&2; exit 1"])
+        .run_quiet()
+        .err()
+        .unwrap();
+    assert_eq!(e.downcast_ref::<CommandError>().unwrap().stderr, "oops\n");
+}
+
+#[test]
+fn command_run_ext_stderr_limit() {
+    let script = "printf 'start-%02000d-end' 0 1>&2; exit 1";
+    let e = Command::new("/bin/sh")
+        .args(["-c", script])
+        .run()
+        .err()
+        .unwrap();
+    let e = e.downcast_ref::<CommandError>().unwrap();
+    assert_eq!(e.stderr.len(), 1024);
+    assert!(e.stderr.ends_with("0-end"));
+    let e = Command::new("/bin/sh")
+        .args(["-c", script])
+        .run_with_stderr_limit(4096)
+        .err()
+        .unwrap();
+    let e = e.downcast_ref::<CommandError>().unwrap();
+    assert!(e.stderr.starts_with("start-0"));
+    assert_eq!(e.stderr.len(), 2010);
+}
+
+#[test]
+fn command_run_ext_clean_env() {
+    let script = r#"test -n "$PATH" && test -z "$HOME" && test "$FOO" = bar"#;
+    Command::new("/bin/sh")
+        .args(["-c", script])
+        .env("FOO", "bar")
+        .run_clean_env(&[])
+        .unwrap();
+    if std::env::var_os("HOME").is_some() {
+        let script = r#"test -n "$HOME""#;
+        Command::new("/bin/sh")
+            .args(["-c", script])
+            .run_clean_env(&["HOME"])
+            .unwrap();
+    }
+}
+
+#[test]
+fn command_run_ext_interactive() {
+    Command::new("true").run_interactive().unwrap();
+    let e = Command::new("/bin/sh")
+        .args(["-c", "exit 1"])
+        .run_interactive()
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Subprocess failed: ExitStatus(unix_wait_status(256))\n"
+    );
+}
+
+#[test]
+fn command_run_ext_new_session() {
+    // The sixth field of /proc/$pid/stat is the session ID
+    let script = r#"set -- $(cat /proc/$$/stat); test "$6" = "$$""#;
+    assert!(Command::new("/bin/sh").args(["-c", script]).run().is_err());
+    Command::new("/bin/sh")
+        .args(["-c", script])
+        .run_in_new_session()
+        .unwrap();
+}
+
+#[test]
+#[allow(unsafe_code)]
+fn command_run_ext_resetting_signals() {
+    // Ignore and block SIGUSR1 in the child, as if inherited from us
+    let inherit_usr1 = |cmd: &mut Command| {
+        // SAFETY: signal(), sigemptyset(), sigaddset() and sigprocmask() are async-signal-safe
+        unsafe {
+            cmd.pre_exec(|| {
+                libc::signal(libc::SIGUSR1, libc::SIG_IGN);
+                let mut set = std::mem::MaybeUninit::<libc::sigset_t>::uninit();
+                libc::sigemptyset(set.as_mut_ptr());
+                libc::sigaddset(set.as_mut_ptr(), libc::SIGUSR1);
+                libc::sigprocmask(libc::SIG_BLOCK, set.as_ptr(), std::ptr::null_mut());
+                Ok(())
+            });
+        }
+    };
+    // Check SIGUSR1 (bit 9 in /proc/$pid/status) is neither ignored nor blocked
+    let script = r#"for f in SigIgn SigBlk; do set -- $(grep "^$f:" /proc/$$/status); test $((0x$2 & 0x200)) -eq 0 || exit 1; done"#;
+    let mut cmd = Command::new("/bin/sh");
+    cmd.args(["-c", script]);
+    inherit_usr1(&mut cmd);
+    assert!(cmd.run().is_err());
+    cmd.run_resetting_signals().unwrap();
+}
+
+#[test]
+fn test_capture_tempdir() {
+    use std::os::fd::AsRawFd;
+    let fd_path =
+        |f: &File| std::fs::read_link(format!("/proc/self/fd/{}", f.as_raw_fd())).unwrap();
+    let _state = crate::testutil::global_state();
+    let td = tempfile::tempdir().unwrap();
+    set_capture_tempdir(Some(td.path()));
+    let f = capture_tempfile().unwrap();
+    // Tempfiles are created with the lock held, so after this returns
+    // no other test will be using the directory.
+    set_capture_tempdir(None);
+    assert!(fd_path(&f).starts_with(td.path()));
+    let f = capture_tempfile().unwrap();
+    assert!(fd_path(&f)
+        .to_str()
+        .unwrap()
+        .starts_with("/memfd:bootc-capture"));
+}
+
+#[test]
+fn test_last_content_from_file() {
+    let mut f = tempfile::tempfile().unwrap();
+    assert_eq!(last_content_from_file(f.try_clone().unwrap(), 10), b"");
+    f.write_all(&[b'x'; 2000]).unwrap();
+    f.write_all(b"0123456789").unwrap();
+    assert_eq!(
+        last_content_from_file(f.try_clone().unwrap(), 10),
+        b"0123456789"
+    );
+    assert_eq!(last_content_from_file(f.try_clone().unwrap(), 4), b"6789");
+    assert_eq!(
+        last_content_from_file(f.try_clone().unwrap(), usize::MAX).len(),
+        2010
+    );
+
+    // Truncation doesn't split a multibyte character
+    let mut f = tempfile::tempfile().unwrap();
+    f.write_all("aé€😀".as_bytes()).unwrap();
+    for (max, expected) in [(4, "😀"), (5, "😀"), (6, "😀"), (7, "€😀"), (9, "é€😀")]
+    {
+        let v = last_content_from_file(f.try_clone().unwrap(), max);
+        assert_eq!(std::str::from_utf8(&v).unwrap(), expected);
+    }
+}
+
+#[test]
+fn test_discard_excess_stderr() {
+    use std::os::unix::fs::MetadataExt;
+    let mut f = tempfile::tempfile().unwrap();
+    f.write_all(b"start\n").unwrap();
+    assert!(!discard_excess_stderr(&f, 1024).unwrap());
+    let chunk = vec![b'x'; 1024 * 1024];
+    for _ in 0..(MAX_STDERR_CAPTURE_BYTES / chunk.len() as u64) {
+        f.write_all(&chunk).unwrap();
+    }
+    f.write_all(b"end\n").unwrap();
+    let len = f.metadata().unwrap().len();
+    assert!(discard_excess_stderr(&f, 1024).unwrap());
+    let meta = f.metadata().unwrap();
+    assert_eq!(meta.len(), len);
+    assert!(meta.blocks() * 512 < MAX_STDERR_CAPTURE_BYTES / 2);
+    let tail = last_utf8_content_from_file(f, 16);
+    assert_eq!(tail, "xxxxxxxxxxxxend\n");
+}
+
+#[test]
+fn test_tail_buffer() {
+    let mut t = TailBuffer::new(4);
+    t.push(b"ab");
+    assert_eq!(t.to_string_lossy(), "ab");
+    t.push(b"cde");
+    assert_eq!(t.to_string_lossy(), "bcde");
+    t.push(b"fghijk");
+    assert_eq!(t.to_string_lossy(), "hijk");
+    let mut t = TailBuffer::new(3);
+    t.push("a€".as_bytes());
+    assert_eq!(t.into_bytes(), "€".as_bytes());
+    let mut t = TailBuffer::new(3);
+    t.push("€".as_bytes());
+    t.push(b"a");
+    assert_eq!(t.into_bytes(), b"a");
+}
+
+#[test]
+fn command_run_ext_with_stderr() {
+    let mut buf = Vec::new();
+    Command::new("/bin/sh")
+        .args(["-c", "echo warning 1>&2"])
+        .run_with_stderr(&mut buf)
+        .unwrap();
+    assert_eq!(buf, b"warning\n");
+    let mut buf = Vec::new();
+    let script = "printf 'start-%02000d-end' 0 1>&2; exit 1";
+    let e = Command::new("/bin/sh")
+        .args(["-c", script])
+        .run_with_stderr(&mut buf)
+        .err()
+        .unwrap();
+    assert_eq!(buf.len(), 2010);
+    let e = e.downcast_ref::<CommandError>().unwrap();
+    assert_eq!(e.stderr.len(), 1024);
+    assert!(e.stderr.ends_with("0-end"));
+}
+
+#[test]
+fn command_run_ext_piped() {
+    Command::new("true").run_piped().unwrap();
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo oops 1>&2; exit 1"])
+        .run_piped()
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Subprocess failed: ExitStatus(unix_wait_status(256))\noops\n"
+    );
+}
+
+#[test]
+fn command_run_ext_timed() {
+    let elapsed = Command::new("sleep").arg("0.1").run_timed().unwrap();
+    assert!(elapsed >= Duration::from_millis(100));
+    assert!(Command::new("false").run_timed().is_err());
+}
+
+#[test]
+fn command_run_ext_priority() {
+    // The nineteenth field of /proc/$pid/stat is the nice value
+    let script = r#"set -- $(cat /proc/$$/stat); test "${19}" = "$0""#;
+    Command::new("/bin/sh")
+        .args(["-c", script, "10"])
+        .run_with_priority(10)
+        .unwrap();
+    Command::new("/bin/sh")
+        .args(["-c", script, "19"])
+        .run_with_priority(100)
+        .unwrap();
+    // This may or may not be permitted, but must not fail
+    Command::new("true").run_with_priority(-20).unwrap();
+    assert!(Command::new("false").run_with_priority(10).is_err());
+}
+
+#[test]
+fn command_run_ext_rlimits() {
+    let td = tempfile::tempdir().unwrap();
+    let script = r#"exec head -c 8192 /dev/zero > "$0""#;
+    let limits = [(Resource::Fsize, 4096)];
+    let e = Command::new("/bin/sh")
+        .args(["-c", script])
+        .arg(td.path().join("f"))
+        .run_with_rlimits(&limits)
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Subprocess was resource-limited: [(Fsize, 4096)]"
+    );
+    let e = e.downcast_ref::<CommandError>().unwrap();
+    assert_eq!(e.status.signal(), Some(libc::SIGXFSZ));
+    Command::new("/bin/sh")
+        .args(["-c", r#"test "$(ulimit -n)" = 42"#])
+        .run_with_rlimits(&[(Resource::Nofile, 42)])
+        .unwrap();
+}
+
+#[test]
+fn command_run_ext_umask() {
+    let td = tempfile::tempdir().unwrap();
+    let path = td.path().join("f");
+    Command::new("touch")
+        .arg(&path)
+        .run_with_umask(0o077)
+        .unwrap();
+    let mode = std::os::unix::fs::PermissionsExt::mode(&path.metadata().unwrap().permissions());
+    assert_eq!(mode & 0o777, 0o600);
+}
+
+#[test]
+fn command_run_ext_fd() {
+    use std::os::fd::AsRawFd;
+    let mut f = tempfile::tempfile().unwrap();
+    f.write_all(b"secret").unwrap();
+    f.seek(std::io::SeekFrom::Start(0)).unwrap();
+    Command::new("/bin/sh")
+        .args(["-c", r#"test "$(cat <&5)" = secret"#])
+        .run_with_fd(f.as_raw_fd(), 5)
+        .unwrap();
+    // The same number in the child, which is otherwise close-on-exec
+    f.seek(std::io::SeekFrom::Start(0)).unwrap();
+    let fd = f.as_raw_fd();
+    Command::new("/bin/sh")
+        .args(["-c", r#"test "$(cat <&$0)" = secret"#])
+        .arg(fd.to_string())
+        .run_with_fd(fd, fd)
+        .unwrap();
+}
+
+#[test]
+fn command_run_ext_run_as() {
+    const NOBODY: u32 = 65534;
+    let td = tempfile::tempdir().unwrap();
+    let marker = td.path().join("marker");
+    if rustix::process::geteuid().is_root() {
+        let script =
+            r#"test "$(id -u)" = "$0" && test "$(id -g)" = "$0" && test "$(id -G)" = "$0""#;
+        Command::new("/bin/sh")
+            .args(["-c", script])
+            .arg(NOBODY.to_string())
+            .run_as(NOBODY, NOBODY)
+            .unwrap();
+    }
+    // Changing to root must fail if we aren't root; and when we are, we use a
+    // new user namespace without any ID mappings to ensure this.
+    let mut cmd = Command::new("touch");
+    cmd.arg(&marker);
+    if rustix::process::geteuid().is_root() {
+        #[allow(unsafe_code)]
+        // SAFETY: unshare() is async-signal-safe
+        unsafe {
+            cmd.pre_exec(|| Ok(rustix::thread::unshare(NamespaceFlags::NEWUSER)?));
+        }
+    }
+    let e = cmd.run_as(0, 0).err().unwrap();
+    assert_eq!(e.to_string(), "Spawning as user 0 and group 0");
+    // In particular, the child was not executed anyway
+    assert!(!marker.exists());
+}
+
+#[test]
+fn command_run_ext_namespace() {
+    let ours = std::fs::read_link("/proc/self/ns/mnt").unwrap();
+    let script = r#"test "$(readlink /proc/self/ns/mnt)" != "$0""#;
+    let r = Command::new("/bin/sh")
+        .args(["-c", script])
+        .arg(&ours)
+        .run_in_namespace(NamespaceFlags::NEWNS);
+    if rustix::process::geteuid().is_root() {
+        r.unwrap();
+    } else {
+        assert!(r.is_err());
+    }
+    // CLONE_VFORK is not valid for unshare()
+    let flags = NamespaceFlags::from_bits_retain(libc::CLONE_VFORK as u32);
+    let e = Command::new("true").run_in_namespace(flags).err().unwrap();
+    assert!(e
+        .to_string()
+        .starts_with("Failed to unshare namespaces (UnshareFlags(0x4000)): "));
+}
+
+#[test]
+fn command_run_ext_in_dir() {
+    let td = tempfile::tempdir().unwrap();
+    Command::new("/bin/sh")
+        .args(["-c", r#"test "$(pwd)" = "$0""#])
+        .arg(td.path())
+        .run_in_dir(td.path())
+        .unwrap();
+    let missing = td.path().join("missing");
+    let e = Command::new("true").run_in_dir(&missing).err().unwrap();
+    assert_eq!(
+        e.to_string(),
+        format!("Spawning in directory {}", missing.display())
+    );
+    // Failures of the child itself are returned unchanged
+    let e = Command::new("false").run_in_dir(td.path()).err().unwrap();
+    assert!(e.downcast_ref::<CommandError>().is_some());
+}
+
+#[test]
+fn command_run_ext_stdout_to_file() {
+    let td = tempfile::tempdir().unwrap();
+    let path = td.path().join("out");
+    Command::new("echo")
+        .arg("hello")
+        .run_stdout_to_file(&path)
+        .unwrap();
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), "hello\n");
+    Command::new("/bin/sh")
+        .args(["-c", "echo partial; exit 1"])
+        .run_stdout_to_file(&path)
+        .err()
+        .unwrap();
+    assert!(!path.exists());
+}
+
+#[test]
+fn command_run_ext_capturing_stderr() {
+    let v = Command::new("/bin/sh")
+        .args(["-c", "echo out; echo warning: deprecated 1>&2"])
+        .run_capturing_stderr()
+        .unwrap();
+    assert_eq!(v, "warning: deprecated\n");
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo oops 1>&2; exit 1"])
+        .run_capturing_stderr()
+        .err()
+        .unwrap();
+    assert_eq!(e.downcast_ref::<CommandError>().unwrap().stderr, "oops\n");
+}
+
+#[test]
+fn command_run_ext_stderr_lines() {
+    let (st, lines) = Command::new("/bin/sh")
+        .args([
+            "-c",
+            "echo level=warn msg=a 1>&2; echo out; echo level=error msg=b 1>&2; exit 2",
+        ])
+        .run_collecting_stderr_lines()
+        .unwrap();
+    assert_eq!(st.code(), Some(2));
+    assert_eq!(lines, ["level=warn msg=a", "level=error msg=b"]);
+    let (st, lines) = Command::new("true").run_collecting_stderr_lines().unwrap();
+    assert!(st.success());
+    assert!(lines.is_empty());
+}
+
+#[test]
+fn command_run_ext_mapping() {
+    const PODMAN_CODES: &[(i32, &str)] = &[
+        (125, "container runtime error"),
+        (127, "container command not found"),
+    ];
+    Command::new("true").run_mapping(PODMAN_CODES).unwrap();
+    let e = Command::new("/bin/sh")
+        .args(["-c", "exit 127"])
+        .run_mapping(PODMAN_CODES)
+        .err()
+        .unwrap();
+    assert_eq!(e.to_string(), "container command not found (exit code 127)");
+    assert_eq!(e.downcast_ref::<CommandError>().unwrap().code(), Some(127));
+    let e = Command::new("/bin/sh")
+        .args(["-c", "exit 1"])
+        .run_mapping(PODMAN_CODES)
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Subprocess failed: ExitStatus(unix_wait_status(256))\n"
+    );
+}
+
+#[test]
+fn command_run_ext_status() {
+    assert!(Command::new("true").run_status().unwrap().success());
+    let st = Command::new("/bin/sh")
+        .args(["-c", "echo oops 1>&2; exit 3"])
+        .run_status()
+        .unwrap();
+    assert_eq!(st.code(), Some(3));
+    assert!(Command::new("/nonexistent/program").run_status().is_err());
+}
+
+#[test]
+fn command_run_ext_capturing() {
+    let o = Command::new("/bin/sh")
+        .args(["-c", "echo out; echo err 1>&2; exit 2"])
+        .run_capturing()
+        .unwrap();
+    assert_eq!(o.status.code(), Some(2));
+    assert_eq!(o.stdout, "out\n");
+    assert_eq!(o.stderr, "err\n");
+    assert!(Command::new("/nonexistent/program")
+        .run_capturing()
+        .is_err());
+}
+
+#[test]
+fn command_run_ext_retries() {
+    let td = tempfile::tempdir().unwrap();
+    let counter = td.path().join("counter");
+    // Fails until it has been invoked three times
+    let mut cmd = Command::new("/bin/sh");
+    cmd.args(["-c", r#"echo x >> "$0"; test $(wc -l < "$0") -ge 3 || { echo fail-$(wc -l < "$0") 1>&2; exit 1; }"#])
+        .arg(&counter);
+    let e = cmd
+        .run_with_retries(2, Duration::from_millis(1))
+        .err()
+        .unwrap();
+    assert_eq!(e.downcast_ref::<CommandError>().unwrap().stderr, "fail-2\n");
+    std::fs::remove_file(&counter).unwrap();
+    cmd.run_with_retries(3, Duration::from_millis(1)).unwrap();
+}
+
+#[test]
+fn command_run_ext_retries_if() {
+    let retry_if = |e: &anyhow::Error| {
+        e.is::<CommandTimedOut>()
+            || e.downcast_ref::<CommandError>().and_then(|e| e.code()) == Some(125)
+    };
+    let td = tempfile::tempdir().unwrap();
+    let counter = td.path().join("counter");
+    // Exits 125 on the first invocation, then times out, then succeeds
+    let mut cmd = Command::new("/bin/sh");
+    cmd.args([
+        "-c",
+        r#"echo x >> "$0"; case $(wc -l < "$0") in 1) exit 125;; 2) sleep 10;; esac"#,
+    ])
+    .arg(&counter);
+    cmd.run_with_retries_if(
+        3,
+        Duration::from_millis(1),
+        Some(Duration::from_millis(200)),
+        retry_if,
+    )
+    .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&counter).unwrap().lines().count(),
+        3
+    );
+    // The timeout is reported after the final attempt
+    std::fs::remove_file(&counter).unwrap();
+    let e = cmd
+        .run_with_retries_if(
+            2,
+            Duration::from_millis(1),
+            Some(Duration::from_millis(200)),
+            retry_if,
+        )
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.downcast_ref::<CommandTimedOut>().unwrap().after,
+        Duration::from_millis(200)
+    );
+    // Other failures are not retried
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo nope 1>&2; exit 1"])
+        .run_with_retries_if(3, Duration::from_millis(1), None, retry_if)
+        .err()
+        .unwrap();
+    assert_eq!(e.downcast_ref::<CommandError>().unwrap().stderr, "nope\n");
+}
+
+#[test]
+fn command_run_ext_mapping_err() {
+    let during_fetch = |e: anyhow::Error| e.context("During image fetch");
+    Command::new("true").run_mapping_err(during_fetch).unwrap();
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo oops 1>&2; exit 1"])
+        .run_mapping_err(during_fetch)
+        .err()
+        .unwrap();
+    assert_eq!(e.to_string(), "During image fetch");
+    assert_eq!(e.downcast_ref::<CommandError>().unwrap().stderr, "oops\n");
+}
+
+#[test]
+fn command_run_ext_observed() {
+    Command::new("true").run_observed().unwrap();
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo oops 1>&2; exit 3"])
+        .run_observed()
+        .err()
+        .unwrap();
+    let e = e.downcast_ref::<CommandError>().unwrap();
+    assert_eq!(e.code(), Some(3));
+    assert_eq!(e.stderr, "oops\n");
+}
+
+#[test]
+fn test_retry_interrupted() {
+    // Fails with EINTR before each read
+    struct Flaky(bool, &'static [u8]);
+    impl Read for Flaky {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0 = !self.0;
+            if self.0 {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+            self.1.read(buf)
+        }
+    }
+    let mut r = RetryInterrupted(Flaky(false, b"hello"));
+    let mut buf = [0u8; 16];
+    assert_eq!(r.read(&mut buf).unwrap(), 5);
+    assert_eq!(r.read(&mut buf).unwrap(), 0);
+    let mut n = 0;
+    assert_eq!(
+        retry_interrupted(|| {
+            n += 1;
+            match n {
+                1 | 2 => Err(std::io::ErrorKind::Interrupted.into()),
+                _ => Ok(n),
+            }
+        })
+        .unwrap(),
+        3
+    );
+}
+
+#[test]
+fn command_run_ext_parse_json_bounded() {
+    let v: serde_json::Value = Command::new("echo")
+        .arg(r#"{"a": 1}"#)
+        .run_and_parse_json_bounded(9)
+        .unwrap();
+    assert_eq!(v, serde_json::json!({"a": 1}));
+    let e = Command::new("/bin/sh")
+        .args(["-c", "yes '[0]'"])
+        .run_and_parse_json_bounded::<serde_json::Value>(4096)
+        .err()
+        .unwrap();
+    assert_eq!(e.to_string(), "Subprocess output exceeded 4096 bytes");
+
+    // As for run_and_parse_json(), a byte order mark is skipped, and errors include context
+    let v: serde_json::Value = Command::new("printf")
+        .arg(r#"\357\273\277{"a": 1}"#)
+        .run_and_parse_json_bounded(4096)
+        .unwrap();
+    assert_eq!(v, serde_json::json!({"a": 1}));
+    let e = Command::new("echo")
+        .arg(r#"{"a": nope}"#)
+        .run_and_parse_json_bounded::<serde_json::Value>(4096)
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        r#"Parsing JSON from stdout at line 1 column 8: {"a": nope}"#
+    );
+}
+
+#[test]
+fn command_run_ext_pty() {
+    let out = Command::new("/bin/sh")
+        .args(["-c", "test -t 0 && test -t 1 && echo tty; echo err 1>&2"])
+        .run_with_pty()
+        .unwrap();
+    assert_eq!(out, "tty\r\nerr\r\n");
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo oops; exit 1"])
+        .run_with_pty()
+        .err()
+        .unwrap();
+    assert_eq!(e.downcast_ref::<CommandError>().unwrap().stderr, "oops\r\n");
+}
+
+#[test]
+fn test_parse_default_timeout() {
+    let parse = |v: &str| parse_default_timeout(std::ffi::OsStr::new(v));
+    assert_eq!(parse("30"), Some(Duration::from_secs(30)));
+    assert_eq!(parse(" 0.5\n"), Some(Duration::from_millis(500)));
+    assert_eq!(parse("0"), None);
+    assert_eq!(parse("-1"), None);
+    assert_eq!(parse("5m"), None);
+}
+
+#[test]
+fn test_wait_logged_timeout() {
+    let mut cmd = Command::new("/bin/sh");
+    cmd.args(["-c", "echo started; exec sleep 10"])
+        .stdout(Stdio::piped());
+    let span = subprocess_span(&cmd);
+    let start = Instant::now();
+    let mut child = cmd.spawn().unwrap();
+    let stdout = child.stdout.take().unwrap();
+    let timeout = Some(Duration::from_millis(100));
+    // The child is terminated while we're reading its output
+    let (r, st) = wait_logged(&span, &cmd, &mut child, start, timeout, || {
+        std::io::read_to_string(stdout)
+    })
+    .unwrap();
+    assert_eq!(r.unwrap(), "started\n");
+    assert!(st.is_none());
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+fn command_run_ext_until_stderr_matches() {
+    let pattern = regex::Regex::new("^error: unauthorized").unwrap();
+    let e = Command::new("/bin/sh")
+        .args([
+            "-c",
+            "echo retrying 1>&2; echo 'error: unauthorized: bad token' 1>&2; sleep 10",
+        ])
+        .run_until_stderr_matches(&pattern)
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Subprocess stderr matched `^error: unauthorized`: error: unauthorized: bad token"
+    );
+    Command::new("/bin/sh")
+        .args(["-c", "echo retrying 1>&2"])
+        .run_until_stderr_matches(&pattern)
+        .unwrap();
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo oops 1>&2; exit 1"])
+        .run_until_stderr_matches(&pattern)
+        .err()
+        .unwrap();
+    assert_eq!(e.downcast_ref::<CommandError>().unwrap().stderr, "oops\n");
+}
+
+#[test]
+fn command_run_ext_parse_env() {
+    let script = r#"printf '%s\n' '# comment' 'ID=fedora' '' 'NAME="Fedora \"Linux\""' "VERSION='40 (Container Image)'" 'EMPTY='"#;
+    let env = Command::new("/bin/sh")
+        .args(["-c", script])
+        .run_and_parse_env()
+        .unwrap();
+    assert_eq!(env.len(), 4);
+    assert_eq!(env["ID"], "fedora");
+    assert_eq!(env["NAME"], "Fedora \"Linux\"");
+    assert_eq!(env["VERSION"], "40 (Container Image)");
+    assert_eq!(env["EMPTY"], "");
+    let e = Command::new("echo")
+        .arg("garbage")
+        .run_and_parse_env()
+        .err()
+        .unwrap();
+    assert_eq!(format!("{e:#}"), "Parsing stdout: Invalid line 1: garbage");
+}
+
+#[test]
+fn command_run_ext_probe() {
+    assert_eq!(
+        Command::new("echo").arg("found").probe().unwrap(),
+        (0, "found\n".to_owned())
+    );
+    assert_eq!(
+        Command::new("/bin/sh")
+            .args(["-c", "echo missing; echo oops 1>&2; exit 3"])
+            .probe()
+            .unwrap(),
+        (3, "missing\n".to_owned())
+    );
+    assert_eq!(
+        Command::new("/bin/sh")
+            .args(["-c", "kill -9 $$"])
+            .probe()
+            .unwrap()
+            .0,
+        137
+    );
+    assert!(Command::new("/nonexistent").probe().is_err());
+}
+
+#[test]
+fn test_check_program_allowed() {
+    let allowed = ["true".to_owned(), "/bin/sh".to_owned()];
+    check_program_allowed(&Command::new("true"), &allowed).unwrap();
+    check_program_allowed(&Command::new("/bin/sh"), &allowed).unwrap();
+    // Resolved via PATH
+    let mut cmd = Command::new("sh");
+    cmd.env("PATH", "/bin");
+    check_program_allowed(&cmd, &allowed).unwrap();
+    let e = check_program_allowed(&Command::new("false"), &allowed)
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Executing `false` is not permitted by the allowed programs policy"
+    );
+}
+
+#[test]
+fn test_metrics() {
+    let _state = crate::testutil::global_state();
+    // Use a unique program name, as other tests may run concurrently
+    let td = tempfile::tempdir().unwrap();
+    let program = td.path().join("bootc-metrics-test");
+    std::os::unix::fs::symlink("/bin/sh", &program).unwrap();
+    Command::new(&program).args(["-c", "true"]).run().unwrap();
+    assert_eq!(dump_metrics().get("bootc-metrics-test"), None);
+    set_metrics_enabled(true);
+    Command::new(&program)
+        .args(["-c", "sleep 0.1"])
+        .run()
+        .unwrap();
+    Command::new(&program)
+        .args(["-c", "exit 1"])
+        .run()
+        .unwrap_err();
+    set_metrics_enabled(false);
+    let metrics = dump_metrics()["bootc-metrics-test"].clone();
+    assert_eq!(metrics.count, 2);
+    assert!(metrics.total >= Duration::from_millis(100));
+}
+
+#[test]
+fn command_run_ext_spawn_capturing() {
+    let (mut child, handles) = Command::new("/bin/sh")
+        .args(["-c", "echo $$"])
+        .stdout(Stdio::piped())
+        .spawn_capturing()
+        .unwrap();
+    let pid = child.id();
+    let mut stdout = String::new();
+    child
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_string(&mut stdout)
+        .unwrap();
+    assert_eq!(stdout.trim(), pid.to_string());
+    handles.wait(&mut child).unwrap();
+
+    let (mut child, handles) = Command::new("/bin/sh")
+        .args(["-c", "echo oops 1>&2; exit 1"])
+        .spawn_capturing()
+        .unwrap();
+    let e = handles.wait(&mut child).err().unwrap();
+    assert_eq!(e.downcast_ref::<CommandError>().unwrap().stderr, "oops\n");
+}
+
+#[test]
+fn command_run_ext_parse_json_validated() {
+    let td = tempfile::tempdir().unwrap();
+    let counter = td.path().join("counter");
+    // Outputs an empty list, then fails, then outputs a complete list
+    let mut cmd = Command::new("/bin/sh");
+    cmd.args([
+        "-c",
+        r#"echo x >> "$0"; case $(wc -l < "$0") in 1) echo '[]';; 2) exit 1;; *) echo '[1, 2]';; esac"#,
+    ])
+    .arg(&counter);
+    let non_empty = |v: &Vec<u32>| !v.is_empty();
+    let v = cmd.run_and_parse_json_validated(3, non_empty).unwrap();
+    assert_eq!(v, [1, 2]);
+    // The final result is returned even if invalid
+    std::fs::remove_file(&counter).unwrap();
+    let v = cmd.run_and_parse_json_validated(1, non_empty).unwrap();
+    assert!(v.is_empty());
+    assert!(cmd.run_and_parse_json_validated(1, non_empty).is_err());
+}
+
+#[test]
+fn command_run_ext_merge_json() {
+    let mut all: Vec<u32> = vec![1];
+    Command::new("echo")
+        .arg("[2, 3]")
+        .run_and_merge_json(&mut all)
+        .unwrap();
+    Command::new("echo")
+        .arg("[]")
+        .run_and_merge_json(&mut all)
+        .unwrap();
+    Command::new("echo")
+        .arg("[4]")
+        .run_and_merge_json(&mut all)
+        .unwrap();
+    assert_eq!(all, [1, 2, 3, 4]);
+    for bad in ["[5, \"x\"]", "{}", "[5] [6]"] {
+        let e = Command::new("echo")
+            .arg(bad)
+            .run_and_merge_json(&mut all)
+            .err()
+            .unwrap();
+        assert_eq!(e.to_string(), "Parsing JSON array from stdout");
+    }
+    assert_eq!(all, [1, 2, 3, 4]);
+}
+
+#[test]
+fn command_run_ext_arg0() {
+    let script = r#"test "$(tr '\0' '\n' < /proc/$$/cmdline | head -n 1)" = bootc-fetch"#;
+    let mut cmd = Command::new("/bin/sh");
+    cmd.args(["-c", script]);
+    cmd.run_with_arg0("bootc-fetch").unwrap();
+    assert!(cmd.run_with_arg0("bootc-other").is_err());
+}
+
+#[test]
+fn command_run_ext_in_scope() {
+    let mut cmd = Command::new("podman");
+    cmd.args(["pull", "quay.io/example"])
+        .env("FOO", "bar")
+        .env_remove("BAZ")
+        .current_dir("/sysroot");
+    let scoped = scope_command(&cmd, "bootc-fetch.scope");
+    crate::testutil::assert_command(
+        &scoped,
+        "systemd-run",
+        &[
+            "--scope",
+            "--quiet",
+            "--collect",
+            "--unit",
+            "bootc-fetch.scope",
+            "--",
+            "podman",
+            "pull",
+            "quay.io/example",
+        ],
+    );
+    crate::testutil::assert_command_env(&scoped, "FOO", Some("bar"));
+    crate::testutil::assert_command_env(&scoped, "BAZ", None);
+    crate::testutil::assert_command_dir(&scoped, Path::new("/sysroot"));
+    // Without systemd, the child is run directly
+    if !libsystemd::daemon::booted() {
+        Command::new("true")
+            .run_in_scope("bootc-test.scope")
+            .unwrap();
+        assert!(Command::new("false")
+            .run_in_scope("bootc-test.scope")
+            .is_err());
+    }
+}
+
+#[test]
+fn command_run_ext_expecting_stdout() {
+    let mut cmd = Command::new("printf");
+    cmd.arg("\\nactive\\nrunning\\n\\n");
+    cmd.run_expecting_stdout("active\nrunning").unwrap();
+    let e = cmd.run_expecting_stdout("active\nstopped").err().unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Unexpected stdout (- expected, + actual):\n active\n-stopped\n+running\n"
+    );
+    assert!(Command::new("false").run_expecting_stdout("").is_err());
+}
+
+#[test]
+fn command_run_ext_capture_stdout_strict() {
+    let out = Command::new("echo")
+        .arg("1.2.3")
+        .run_and_capture_stdout_strict()
+        .unwrap();
+    assert_eq!(out, "1.2.3\n");
+    let e = Command::new("printf")
+        .arg("ab\\377c")
+        .run_and_capture_stdout_strict()
+        .err()
+        .unwrap();
+    assert_eq!(e.to_string(), "Invalid UTF-8 in stdout at byte offset 2");
+}
+
+#[test]
+#[allow(unsafe_code)]
+fn command_run_ext_spawn_timeout() {
+    let budget = Duration::from_millis(200);
+    Command::new("true").run_with_spawn_timeout(budget).unwrap();
+    let mut cmd = Command::new("/bin/sh");
+    cmd.args(["-c", "echo oops 1>&2; exit 1"]);
+    let e = cmd.run_with_spawn_timeout(budget).err().unwrap();
+    assert_eq!(e.downcast_ref::<CommandError>().unwrap().stderr, "oops\n");
+    // The configuration is retained
+    assert_eq!(cmd.get_args().count(), 2);
+
+    // Spawning doesn't complete until exec, so simulate a slow spawn
+    let mut cmd = Command::new("true");
+    cmd.arg("ignored").env("FOO", "bar");
+    // SAFETY: nanosleep() is async-signal-safe
+    unsafe {
+        cmd.pre_exec(|| {
+            std::thread::sleep(Duration::from_secs(1));
+            Ok(())
+        });
+    }
+    let e = cmd.run_with_spawn_timeout(budget).err().unwrap();
+    assert_eq!(e.to_string(), "Timed out after 200ms spawning true");
+    // We're left with a copy, without the hook
+    assert_eq!(cmd.get_args().collect::<Vec<_>>(), ["ignored"]);
+    assert_eq!(cmd.get_envs().count(), 1);
+    cmd.run_with_spawn_timeout(budget).unwrap();
+}
+
+#[test]
+fn command_run_ext_discarding() {
+    Command::new("/bin/sh")
+        .args(["-c", "echo noise; echo more 1>&2"])
+        .run_discarding()
+        .unwrap();
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo oops 1>&2; exit 2"])
+        .run_discarding()
+        .err()
+        .unwrap();
+    let e = e.downcast_ref::<CommandError>().unwrap();
+    assert_eq!(e.code(), Some(2));
+    assert_eq!(e.stderr, "");
+}
+
+#[test]
+fn command_run_ext_parse_paths() {
+    let td = tempfile::tempdir().unwrap();
+    std::fs::write(td.path().join("a b"), "").unwrap();
+    std::fs::write(td.path().join("c\nd"), "").unwrap();
+    let find = |arg| -> Vec<PathBuf> {
+        let mut paths = Command::new("find")
+            .args([".", "-type", "f", arg])
+            .current_dir(td.path())
+            .run_and_parse_paths(arg == "-print0")
+            .unwrap();
+        paths.sort();
+        paths
+    };
+    assert_eq!(find("-print0"), [Path::new("./a b"), Path::new("./c\nd")]);
+    assert_eq!(
+        find("-print"),
+        [Path::new("./a b"), Path::new("./c"), Path::new("d")]
+    );
+}
+
+#[test]
+fn command_run_ext_allowing() {
+    assert_eq!(Command::new("true").run_allowing(&[1]).unwrap(), 0);
+    assert_eq!(Command::new("false").run_allowing(&[1]).unwrap(), 1);
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo oops 1>&2; exit 2"])
+        .run_allowing(&[1])
+        .err()
+        .unwrap();
+    let e = e.downcast_ref::<CommandError>().unwrap();
+    assert_eq!(e.code(), Some(2));
+    assert_eq!(e.stderr, "oops\n");
+}
+
+#[test]
+fn command_run_ext_dry_run() {
+    set_dry_run(true);
+    Command::new("false").run().unwrap();
+    Command::new("false").run_with_stdin(b"foo").unwrap();
+    Command::new("false")
+        .run_in_scope("bootc-test.scope")
+        .unwrap();
+    assert!(Command::new("echo")
+        .arg("42")
+        .run_and_parse_json::<u32>()
+        .is_err());
+    set_dry_run(false);
+    assert!(Command::new("false").run().is_err());
+}
+
+#[test]
+fn command_run_ext_signalled() {
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo oops 1>&2; kill -9 $$"])
+        .run()
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Subprocess killed by signal SIGKILL (9)\noops\n"
+    );
+    assert!(e.downcast_ref::<CommandError>().unwrap().signalled());
+    let e = Command::new("/bin/sh")
+        .args(["-c", "kill -s RTMIN $$"])
+        .run()
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        format!("Subprocess killed by signal {}\n", libc::SIGRTMIN())
+    );
+}
+
+#[test]
+fn test_verbose_errors() {
+    let mut cmd = Command::new("/bin/sh");
+    cmd.args(["-c", "echo oops 1>&2; exit 1", "--password", "hunter2"]);
+    let e = cmd.run().err().unwrap();
+    // Record it directly rather than enabling this globally, which would affect other tests
+    let e = e.downcast::<CommandError>().unwrap().record_cmdline(&cmd);
+    assert_eq!(
+        e.to_string(),
+        "Subprocess failed: ExitStatus(unix_wait_status(256))\noops\ncommand: /bin/sh -c echo oops 1>&2; exit 1 --password ****"
+    );
+
+    // Each part starts on a new line, even if stderr doesn't end with one
+    let mut cmd = Command::new("/bin/sh");
+    cmd.args(["-c", "printf oops 1>&2; exit 1"]);
+    let e = cmd.run().err().unwrap();
+    let e = e.downcast::<CommandError>().unwrap().record_cmdline(&cmd);
+    assert_eq!(
+        e.to_string(),
+        "Subprocess failed: ExitStatus(unix_wait_status(256))\noops\ncommand: /bin/sh -c printf oops 1>&2; exit 1"
+    );
+}
+
+#[test]
+fn test_command_error_core_dumped() {
+    // The wait status of a child killed by SIGSEGV which dumped core
+    let st = ExitStatus::from_raw(libc::SIGSEGV | 0x80);
+    let e = CommandError::new(st, b"oops\n".to_vec());
+    assert!(e.core_dumped());
+    let msg = e.to_string();
+    assert!(msg.starts_with("Subprocess killed by signal SIGSEGV (11) (core dumped"));
+    assert!(msg.ends_with(")\noops\n"));
+    let e = CommandError::new(ExitStatus::from_raw(libc::SIGSEGV), Vec::new());
+    assert!(!e.core_dumped());
+    assert_eq!(e.to_string(), "Subprocess killed by signal SIGSEGV (11)\n");
+}
+
+#[test]
+fn test_run_config() {
+    RunConfig::new().run(&mut Command::new("true")).unwrap();
+    let script = "echo noise; printf 'start-%02000d-end' 0 1>&2; exit 1";
+    let e = RunConfig::new()
+        .stderr_limit(4096)
+        .quiet_stdout(true)
+        .clean_env(&[])
+        .timeout(Duration::from_secs(60))
+        .run(Command::new("/bin/sh").args(["-c", script]))
+        .err()
+        .unwrap();
+    assert_eq!(e.downcast_ref::<CommandError>().unwrap().stderr.len(), 2010);
+    let e = RunConfig::new()
+        .timeout(Duration::from_millis(100))
+        .stderr_limit(3)
+        .run(Command::new("/bin/sh").args(["-c", "echo started 1>&2; exec sleep 60"]))
+        .err()
+        .unwrap();
+    assert_eq!(e.downcast_ref::<CommandTimedOut>().unwrap().stderr, "ed\n");
+    let script = "printf 'line %s\\n' $(seq 1 12) 1>&2; exit 1";
+    let e = RunConfig::new()
+        .stderr_limit(16)
+        .stderr_line_numbers(true)
+        .run(Command::new("/bin/sh").args(["-c", script]))
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.downcast_ref::<CommandError>().unwrap().stderr,
+        "11: line 11\n12: line 12\n"
+    );
+}
+
+#[test]
+fn command_run_ext_timeout() {
+    Command::new("true")
+        .run_with_timeout(Duration::from_secs(60))
+        .unwrap();
+    assert!(Command::new("false")
+        .run_with_timeout(Duration::from_secs(60))
+        .is_err());
+
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo started 1>&2; exec sleep 60"])
+        .run_with_timeout(Duration::from_millis(100))
+        .err()
+        .unwrap();
+    let e = e.downcast_ref::<CommandTimedOut>().unwrap();
+    assert_eq!(e.after, Duration::from_millis(100));
+    assert_eq!(e.stderr, "started\n");
+
+    // A child ignoring SIGTERM is killed after the grace period
+    let start = Instant::now();
+    let e = Command::new("/bin/sh")
+        .args(["-c", "trap '' TERM; while :; do sleep 0.1; done"])
+        .run_with_timeout(Duration::from_millis(100))
+        .err()
+        .unwrap();
+    assert!(e.is::<CommandTimedOut>());
+    assert!(start.elapsed() >= TIMEOUT_GRACE_PERIOD);
+}
+
+#[test]
+fn command_run_ext_json() {
+    #[derive(serde::Deserialize)]
+    struct Foo {
+        a: String,
+        b: u32,
+    }
+    let v: Foo = Command::new("echo")
+        .arg(r##"{"a": "somevalue", "b": 42}"##)
+        .run_and_parse_json()
+        .unwrap();
+    assert_eq!(v.a, "somevalue");
+    assert_eq!(v.b, 42);
+
+    let e = Command::new("echo")
+        .arg("warning: something")
+        .run_and_parse_json::<Foo>()
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Parsing JSON from stdout at line 1 column 1: warning: something"
+    );
+    let script = r#"printf '{\n  "a": "x",\n  "b": "%s"\n}\n' $(seq -s, 100)"#;
+    let e = Command::new("/bin/sh")
+        .args(["-c", script])
+        .run_and_parse_json::<Foo>()
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Parsing JSON from stdout at line 3 column 300: ...4,75,76,77,78,79,80,81,82,83,84,85,86,87,88,89,90,91,92,93,94,95,96,97,98,99,100\""
+    );
+    // A child killed while writing is described as such
+    let e = Command::new("/bin/sh")
+        .args(["-c", r#"echo '{"a": "x",'; kill -9 $$"#])
+        .run_and_parse_json::<Foo>()
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Subprocess was terminated before completing output"
+    );
+    let e = e.downcast_ref::<CommandError>().unwrap();
+    assert_eq!(e.status.signal(), Some(libc::SIGKILL));
+    assert_eq!(e.stdout.as_deref(), Some("{\"a\": \"x\",\n"));
+    // A byte order mark and leading blank lines are tolerated
+    let v: Foo = Command::new("/bin/sh")
+        .args(["-c", r#"printf '\357\273\277\n\n  {"a": "x", "b": 1}\n'"#])
+        .run_and_parse_json()
+        .unwrap();
+    assert_eq!(v.b, 1);
+    let e = Command::new("/bin/sh")
+        .args(["-c", r#"printf '\357\273\277oops'"#])
+        .run_and_parse_json::<Foo>()
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Parsing JSON from stdout at line 1 column 1: oops"
+    );
+
+    let v: Foo = Command::new("/bin/sh")
+        .args(["-c", r#"echo '{"a": "x", "b": 1}' 1>&2"#])
+        .run_and_parse_json_from_stderr()
+        .unwrap();
+    assert_eq!(v.a, "x");
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo oops 1>&2; exit 1"])
+        .run_and_parse_json_from_stderr::<Foo>()
+        .err()
+        .unwrap();
+    assert_eq!(e.downcast_ref::<CommandError>().unwrap().stderr, "oops\n");
+}
+
+#[test]
+fn command_run_ext_json_failed() {
+    let e = Command::new("/bin/sh")
+        .args([
+            "-c",
+            r#"echo '{"error": "oops"}'; echo failed 1>&2; exit 1"#,
+        ])
+        .run_and_parse_json::<serde_json::Value>()
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Subprocess failed: ExitStatus(unix_wait_status(256))\nfailed\nstdout: {\"error\": \"oops\"}\n"
+    );
+    let e = e.downcast_ref::<CommandError>().unwrap();
+    assert_eq!(e.stdout_json().unwrap()["error"], "oops");
+
+    let e = Command::new("/bin/sh")
+        .args([
+            "-c",
+            r#"echo '{"error": "oops"}'; printf failed 1>&2; exit 1"#,
+        ])
+        .run_and_parse_json::<serde_json::Value>()
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Subprocess failed: ExitStatus(unix_wait_status(256))\nfailed\nstdout: {\"error\": \"oops\"}\n"
+    );
+}
+
+#[test]
+fn command_run_ext_json_progress() {
+    #[derive(serde::Deserialize)]
+    struct Progress {
+        done: u32,
+    }
+    #[derive(serde::Deserialize)]
+    struct Summary {
+        digest: String,
+    }
+    let script = r#"for i in 1 2 3; do echo "{\"done\": $i}"; done; echo '{"digest": "sha256:x"}'"#;
+    let mut events = Vec::new();
+    let r: Summary = Command::new("/bin/sh")
+        .args(["-c", script])
+        .run_with_json_progress(|p: Progress| events.push(p.done))
+        .unwrap();
+    assert_eq!(events, [1, 2, 3]);
+    assert_eq!(r.digest, "sha256:x");
+
+    let script = r#"echo '{"done": 1}'; echo garbage; echo '{"digest": "sha256:x"}'"#;
+    let e = Command::new("/bin/sh")
+        .args(["-c", script])
+        .run_with_json_progress::<Progress, Summary>(|_| {})
+        .err()
+        .unwrap();
+    assert_eq!(e.to_string(), "Parsing JSON from stdout line 2");
+    // The result is never passed as progress, even if it could be parsed as such
+    let script = r#"echo '{"done": 1}'; echo '{"done": 2}'"#;
+    let mut events = Vec::new();
+    let r: serde_json::Value = Command::new("/bin/sh")
+        .args(["-c", script])
+        .run_with_json_progress(|p: serde_json::Value| events.push(p))
+        .unwrap();
+    assert_eq!(events, [serde_json::json!({"done": 1})]);
+    assert_eq!(r, serde_json::json!({"done": 2}));
+    let e = Command::new("true")
+        .run_with_json_progress::<Progress, Summary>(|_| {})
+        .err()
+        .unwrap();
+    assert_eq!(e.to_string(), "Parsing JSON from stdout: no output");
+}
+
+#[test]
+fn command_run_ext_json_stream() {
+    let v: Vec<serde_json::Value> = Command::new("printf")
+        .arg(r#"{"a":1}{"a":2} {"a":3}"#)
+        .run_and_parse_json_stream()
+        .unwrap();
+    assert_eq!(v.len(), 3);
+    assert_eq!(v[2]["a"], 3);
+    let v: Vec<u32> = Command::new("true").run_and_parse_json_stream().unwrap();
+    assert!(v.is_empty());
+    let e = Command::new("printf")
+        .arg(r#"{"a":1}{"a":"#)
+        .run_and_parse_json_stream::<serde_json::Value>()
+        .err()
+        .unwrap();
+    assert_eq!(e.to_string(), "Parsing JSON from stdout at offset 7");
+}
+
+#[test]
+fn command_run_ext_json_with() {
+    let n = Command::new("echo")
+        .arg(r#"{"a": 1} {"a": 2} {"a": 3}"#)
+        .run_and_parse_json_with(|r| {
+            let mut n = 0;
+            for v in serde_json::Deserializer::from_reader(r).into_iter::<serde_json::Value>() {
+                n += v?["a"].as_u64().unwrap();
+            }
+            Ok(n)
+        })
+        .unwrap();
+    assert_eq!(n, 6);
+    let e = Command::new("false")
+        .run_and_parse_json_with(|_| Ok(()))
+        .err()
+        .unwrap();
+    assert!(e.downcast_ref::<CommandError>().is_some());
+}
+
+#[test]
+fn command_run_ext_json_versioned() {
+    #[derive(serde::Deserialize)]
+    struct Foo {
+        a: u32,
+    }
+    let v: Foo = Command::new("echo")
+        .arg(r#"{"version": "1", "a": 42}"#)
+        .run_and_parse_json_versioned("1")
+        .unwrap();
+    assert_eq!(v.a, 42);
+    let v: Foo = Command::new("echo")
+        .arg(r#"{"schemaVersion": 2, "a": 42}"#)
+        .run_and_parse_json_versioned("2")
+        .unwrap();
+    assert_eq!(v.a, 42);
+    let e = Command::new("echo")
+        .arg(r#"{"version": "2", "a": 42}"#)
+        .run_and_parse_json_versioned::<Foo>("1")
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Unexpected JSON output version 2, expected 1"
+    );
+    let e = Command::new("echo")
+        .arg(r#"{"a": 42}"#)
+        .run_and_parse_json_versioned::<Foo>("1")
+        .err()
+        .unwrap();
+    assert_eq!(e.to_string(), "Missing version field in JSON output");
+}
+
+#[test]
+fn command_run_ext_json_or_default() {
+    let v: Vec<u32> = Command::new("true")
+        .run_and_parse_json_or_default()
+        .unwrap();
+    assert!(v.is_empty());
+    let v: Vec<u32> = Command::new("echo")
+        .run_and_parse_json_or_default()
+        .unwrap();
+    assert!(v.is_empty());
+    let v: Vec<u32> = Command::new("echo")
+        .arg("[1]")
+        .run_and_parse_json_or_default()
+        .unwrap();
+    assert_eq!(v, [1]);
+    assert!(Command::new("false")
+        .run_and_parse_json_or_default::<Vec<u32>>()
+        .is_err());
+}
+
+#[test]
+fn command_run_ext_yaml() {
+    #[derive(serde::Deserialize)]
+    struct Foo {
+        a: String,
+        b: Vec<u32>,
+    }
+    let v: Foo = Command::new("printf")
+        .arg("a: somevalue\nb:\n  - 1\n  - 2\n")
+        .run_and_parse_yaml()
+        .unwrap();
+    assert_eq!(v.a, "somevalue");
+    assert_eq!(v.b, [1, 2]);
+    let e = Command::new("true")
+        .run_and_parse_yaml::<Option<Foo>>()
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Parsing YAML from stdout: unexpected empty output"
+    );
+    let e = Command::new("echo")
+        .arg("[")
+        .run_and_parse_yaml::<Foo>()
+        .err()
+        .unwrap();
+    assert_eq!(e.to_string(), "Parsing YAML from stdout: [\n");
+    assert!(Command::new("false").run_and_parse_yaml::<Foo>().is_err());
+}
+
+#[test]
+fn command_run_ext_json_lines() {
+    let v: Vec<u32> = Command::new("printf")
+        .arg("1\n\n2\n3")
+        .run_and_parse_json_lines()
+        .unwrap();
+    assert_eq!(v, [1, 2, 3]);
+    let v: Vec<u32> = Command::new("true").run_and_parse_json_lines().unwrap();
+    assert!(v.is_empty());
+    let e = Command::new("printf")
+        .arg("1\nfoo\n")
+        .run_and_parse_json_lines::<u32>()
+        .err()
+        .unwrap();
+    assert_eq!(e.to_string(), "Parsing JSON from stdout line 2");
+}
+
+#[test]
+fn test_cmd_macro() {
+    let program = "echo";
+    let imgref = String::from("docker://example");
+    let c = cmd!(program, "inspect", &imgref, std::path::Path::new("/foo"),);
+    crate::testutil::assert_command(&c, "echo", &["inspect", "docker://example", "/foo"]);
+    crate::testutil::assert_command(&cmd!("true"), "true", &[]);
+    cmd!("true").run().unwrap();
+}
+
+#[test]
+fn test_redacted_cmdline() {
+    let mut cmd = Command::new("skopeo");
+    cmd.args([
+        "copy",
+        "--creds",
+        "user:hunter2",
+        "--dest-creds=user:hunter2",
+        "--mytoken",
+        "secret",
+        "docker://example",
+    ]);
+    assert_eq!(
+        redacted_cmdline(&cmd, &[]),
+        "skopeo copy --creds **** --dest-creds=**** --mytoken secret docker://example"
+    );
+    assert_eq!(
+        redacted_cmdline(&cmd, &["--mytoken"]),
+        "skopeo copy --creds **** --dest-creds=**** --mytoken **** docker://example"
+    );
+}
+
+#[test]
+fn command_run_ext_capture_stdout() {
+    let v = Command::new("echo")
+        .arg("hello")
+        .run_and_capture_stdout()
+        .unwrap();
+    assert_eq!(v, "hello\n");
+    let v = Command::new("true").run_and_capture_stdout().unwrap();
+    assert_eq!(v, "");
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo out; echo oops 1>&2; exit 1"])
+        .run_and_capture_stdout()
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Subprocess failed: ExitStatus(unix_wait_status(256))\noops\n"
+    );
+}
+
+#[test]
+fn command_run_ext_stdin_reader() {
+    let td = tempfile::tempdir().unwrap();
+    let path = td.path().join("f");
+    // Larger than a pipe buffer
+    let data = "x".repeat(1024 * 1024);
+    std::fs::write(&path, &data).unwrap();
+    Command::new("/bin/sh")
+        .args(["-c", r#"test "$(wc -c)" = "$0""#])
+        .arg(data.len().to_string())
+        .run_with_stdin_reader(File::open(&path).unwrap())
+        .unwrap();
+    // The child may exit without reading everything
+    Command::new("true")
+        .run_with_stdin_reader(File::open(&path).unwrap())
+        .unwrap();
+}
+
+#[test]
+fn command_run_ext_journaled() {
+    Command::new("true").run_journaled().unwrap();
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo a; echo b 1>&2; echo c; exit 1"])
+        .run_journaled()
+        .err()
+        .unwrap();
+    // The output is in the order it was written
+    assert_eq!(
+        e.downcast_ref::<CommandError>().unwrap().stderr,
+        "a\nb\nc\n"
+    );
+}
+
+#[test]
+fn command_run_ext_ensure_exists() {
+    let p = Command::new("sh").ensure_exists().unwrap();
+    assert!(p.is_absolute());
+    assert!(p.ends_with("sh"));
+    let e = Command::new("nonexistent-program-bootc")
+        .ensure_exists()
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "program `nonexistent-program-bootc` not found in PATH"
+    );
+    // PATH set on the command is used
+    let td = tempfile::tempdir().unwrap();
+    assert!(Command::new("sh")
+        .env("PATH", td.path())
+        .ensure_exists()
+        .is_err());
+    assert_eq!(
+        Command::new("/bin/sh").ensure_exists().unwrap(),
+        Path::new("/bin/sh")
+    );
+    // Not executable
+    let f = td.path().join("f");
+    std::fs::write(&f, "").unwrap();
+    assert!(Command::new(&f).ensure_exists().is_err());
+}
+
+#[test]
+fn command_run_ext_shell_metachars() {
+    let mut cmd = Command::new("podman");
+    cmd.args(["run", "--rm", "quay.io/example"]);
+    assert!(!cmd.warn_on_shell_metachars());
+    for arg in ["$HOME", "a|b", "a; b", "`id`"] {
+        assert!(Command::new("echo").arg(arg).warn_on_shell_metachars());
+    }
+}
+
+#[test]
+fn command_run_ext_peek_stdout() {
+    // This would otherwise write forever
+    let v = Command::new("yes").run_peek_stdout(4).unwrap();
+    assert_eq!(v, b"y\ny\n");
+    let v = Command::new("echo")
+        .arg("abc")
+        .run_peek_stdout(100)
+        .unwrap();
+    assert_eq!(v, b"abc\n");
+    let e = Command::new("/bin/sh")
+        .args(["-c", "echo abc; exit 1"])
+        .run_peek_stdout(2)
+        .err()
+        .unwrap();
+    assert!(e.downcast_ref::<CommandError>().is_some());
+}
+
+#[test]
+fn command_run_ext_stdin() {
+    Command::new("/bin/sh")
+        .args(["-c", "test \"$(cat)\" = somedata"])
+        .run_with_stdin(b"somedata")
+        .unwrap();
+    // Larger than a pipe buffer, and the child not reading it all
+    let buf = vec![b'x'; 1024 * 1024];
+    Command::new("true").run_with_stdin(&buf).unwrap();
+    let e = Command::new("/bin/sh")
+        .args(["-c", "cat >/dev/null; echo oops 1>&2; exit 1"])
+        .run_with_stdin(&buf)
+        .err()
+        .unwrap();
+    assert_eq!(
+        e.to_string(),
+        "Subprocess failed: ExitStatus(unix_wait_status(256))\noops\n"
+    );
+}
+
+#[test]
+fn command_run_ext_streaming_lines() {
+    let mut lines = Vec::new();
+    Command::new("printf")
+        .arg("a\nb\n\nc")
+        .run_streaming_lines(|l| lines.push(l.to_owned()))
+        .unwrap();
+    assert_eq!(lines, ["a", "b", "", "c"]);
+    let r = Command::new("/bin/sh")
+        .args(["-c", "echo a; exit 1"])
+        .run_streaming_lines(|_| {});
+    assert!(r.is_err());
+}
+
+#[test]
+fn test_command_runner() {
+    fn kernel_version(runner: &impl CommandRunner) -> Result<String> {
+        let v = runner.run_and_capture_stdout(Command::new("uname").arg("-r"))?;
+        Ok(v.trim().to_owned())
+    }
+    let mut mock = MockCommandRunner::default();
+    mock.expect(&["uname", "-r"], 0, "6.9.0\n")
+        .expect(&["skopeo", "inspect"], 0, r#"{"a": 42}"#)
+        .expect(&["false"], 1, "");
+    assert_eq!(kernel_version(&mock).unwrap(), "6.9.0");
+    let v: serde_json::Value = mock
+        .run_and_parse_json(Command::new("skopeo").arg("inspect"))
+        .unwrap();
+    assert_eq!(v["a"], 42);
+    let e = mock.run(&mut Command::new("false")).err().unwrap();
+    assert_eq!(e.downcast_ref::<CommandError>().unwrap().code(), Some(1));
+    assert!(mock.run(&mut Command::new("true")).is_err());
+
+    HostCommandRunner.run(&mut Command::new("true")).unwrap();
+    assert!(!kernel_version(&HostCommandRunner).unwrap().is_empty());
+}
+
+#[test]
+fn command_pipeline() {
+    let mut echo = Command::new("printf");
+    echo.arg("foo\nbar\nbaz\n");
+    let mut grep = Command::new("grep");
+    grep.arg("ba");
+    let mut wc = Command::new("wc");
+    wc.arg("-l");
+    let out = CommandPipeline::new([echo, grep, wc]).run().unwrap();
+    assert_eq!(out.trim(), "2");
+
+    let mut echo = Command::new("echo");
+    echo.arg("foo");
+    let mut fail = Command::new("/bin/sh");
+    fail.args(["-c", "cat >/dev/null; echo oops 1>&2; exit 1"]);
+    let e = CommandPipeline::new([echo, fail, Command::new("cat")])
+        .run()
+        .err()
+        .unwrap();
+    assert_eq!(e.to_string(), "Pipeline stage 1 (/bin/sh) failed");
+    let e = e.downcast_ref::<CommandError>().unwrap();
+    assert_eq!(e.stderr, "oops\n");
+
+    assert!(CommandPipeline::new([]).run().is_err());
+
+    // The upstream stage must see EPIPE once the downstream one exits
+    let mut head = Command::new("head");
+    head.arg("-n1");
+    let out = CommandPipeline::new([Command::new("yes"), head])
+        .run_with_deadline(Instant::now() + Duration::from_secs(60))
+        .unwrap();
+    assert_eq!(out, "y\n");
+}
+
+#[test]
+fn command_pipeline_deadline() {
+    let mut echo = Command::new("echo");
+    echo.arg("foo");
+    let out = CommandPipeline::new([echo, Command::new("cat")])
+        .run_with_deadline(Instant::now() + Duration::from_secs(60))
+        .unwrap();
+    assert_eq!(out, "foo\n");
+
+    let mut sleep = Command::new("sleep");
+    sleep.arg("60");
+    let start = Instant::now();
+    let e = CommandPipeline::new([sleep, Command::new("cat")])
+        .run_with_deadline(start + Duration::from_millis(200))
+        .err()
+        .unwrap();
+    assert!(start.elapsed() < Duration::from_secs(30));
+    assert_eq!(
+        e.to_string(),
+        "Pipeline deadline exceeded while stage 0 (sleep) was running"
+    );
+    assert!(e.downcast_ref::<CommandTimedOut>().is_some());
+}
+
+#[test]
+fn async_command_run_ext() {
+    use tokio::process::Command as AsyncCommand;
+    crate::testutil::block_on(async {
+        AsyncCommand::new("true").run().await.unwrap();
+        assert!(AsyncCommand::new("false").run().await.is_err());
+
+        let v: Vec<u32> = AsyncCommand::new("echo")
+            .arg("[1, 2]")
+            .run_and_parse_json()
+            .await
+            .unwrap();
+        assert_eq!(v, [1, 2]);
+        let r = AsyncCommand::new("false")
+            .run_and_parse_json::<Vec<u32>>()
+            .await;
+        assert!(r.is_err());
+
+        // Dropping the future kills the child
+        let td = tempfile::tempdir().unwrap();
+        let pidfile = td.path().join("pid");
+        let mut cmd = AsyncCommand::new("/bin/sh");
+        cmd.args(["-c", r#"echo $$ > "$0"; exec sleep 60"#])
+            .arg(&pidfile);
+        let r = tokio::time::timeout(Duration::from_millis(500), cmd.run()).await;
+        assert!(r.is_err());
+        let pid = std::fs::read_to_string(&pidfile).unwrap();
+        let stat = format!("/proc/{}/stat", pid.trim());
+        // The child may not have been reaped yet, but it must exit
+        let killed = (0..100).any(|_| {
+            let state = std::fs::read_to_string(&stat).unwrap_or_default();
+            if state.is_empty() || state.contains(") Z ") {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+            false
+        });
+        assert!(killed);
+
+        let token = CancellationToken::new();
+        AsyncCommand::new("true")
+            .run_cancellable(token.clone())
+            .await
+            .unwrap();
+        let mut cmd = AsyncCommand::new("/bin/sh");
+        cmd.args(["-c", "echo started 1>&2; exec sleep 60"]);
+        let canceller = token.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            canceller.cancel();
+        });
+        let e = cmd.run_cancellable(token).await.err().unwrap();
+        assert_eq!(
+            e.downcast_ref::<CommandCancelled>().unwrap().stderr,
+            "started\n"
+        );
+    });
+}
+
+#[test]
+fn async_command_run_ext_timeout() {
+    use tokio::process::Command as AsyncCommand;
+    crate::testutil::block_on(async {
+        let timeout = Duration::from_millis(100);
+        AsyncCommand::new("true")
+            .run_with_timeout(timeout)
+            .await
+            .unwrap();
+        let e = AsyncCommand::new("false")
+            .run_with_timeout(timeout)
+            .await
+            .err()
+            .unwrap();
+        assert!(e.is::<CommandError>());
+        let e = AsyncCommand::new("/bin/sh")
+            .args(["-c", "echo started 1>&2; exec sleep 60"])
+            .run_with_timeout(timeout)
+            .await
+            .err()
+            .unwrap();
+        let e = e.downcast_ref::<CommandTimedOut>().unwrap();
+        assert_eq!(e.after, timeout);
+        assert_eq!(e.stderr, "started\n");
+    });
+}
+
+#[test]
+fn async_command_run_ext_deadline() {
+    use tokio::process::Command as AsyncCommand;
+    crate::testutil::block_on(async {
+        let short = Duration::from_millis(50);
+        let long = Duration::from_secs(60);
+        let r = AsyncCommand::new("true")
+            .run_with_deadline(long)
+            .await
+            .unwrap();
+        assert!(matches!(r, DeadlineOutcome::Exited));
+        let e = AsyncCommand::new("/bin/sh")
+            .args(["-c", "echo oops 1>&2; exit 1"])
+            .run_with_deadline(long)
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.downcast_ref::<CommandError>().unwrap().stderr, "oops\n");
+
+        let r = AsyncCommand::new("sleep")
+            .arg("0.5")
+            .run_with_deadline(short)
+            .await
+            .unwrap();
+        let DeadlineOutcome::Running(child) = r else {
+            panic!("Expected child to still be running");
+        };
+        let DeadlineOutcome::Running(child) = child.wait_with_deadline(short).await.unwrap() else {
+            panic!("Expected child to still be running");
+        };
+        child.wait().await.unwrap();
+
+        let r = AsyncCommand::new("sleep")
+            .arg("60")
+            .run_with_deadline(short)
+            .await
+            .unwrap();
+        let DeadlineOutcome::Running(child) = r else {
+            panic!("Expected child to still be running");
+        };
+        let st = child.terminate().await.unwrap();
+        assert_eq!(st.signal(), Some(libc::SIGTERM));
+    });
+}
+
+#[test]
+fn async_command_run_ext_stream() {
+    use futures_core::Stream;
+    use tokio::process::Command as AsyncCommand;
+    async fn collect(mut s: CommandStream) -> (Vec<u8>, Option<anyhow::Error>) {
+        let mut buf = Vec::new();
+        while let Some(r) =
+            std::future::poll_fn(|cx| std::pin::Pin::new(&mut s).poll_next(cx)).await
+        {
+            match r {
+                Ok(chunk) => buf.extend_from_slice(&chunk),
+                Err(e) => return (buf, Some(e)),
+            }
+        }
+        (buf, None)
+    }
+    crate::testutil::block_on(async {
+        let s = AsyncCommand::new("/bin/sh")
+            .args(["-c", "echo foo; sleep 0.1; echo bar"])
+            .run_stream()
+            .unwrap();
+        let (buf, e) = collect(s).await;
+        assert_eq!(buf, b"foo\nbar\n");
+        assert!(e.is_none());
+
+        let s = AsyncCommand::new("/bin/sh")
+            .args(["-c", "echo foo; echo oops 1>&2; exit 1"])
+            .run_stream()
+            .unwrap();
+        let (buf, e) = collect(s).await;
+        assert_eq!(buf, b"foo\n");
+        let e = e.unwrap();
+        assert_eq!(e.downcast_ref::<CommandError>().unwrap().stderr, "oops\n");
+    });
+}
+
+#[test]
+fn test_pre_spawn_hook() {
+    static SEEN: Mutex<Vec<String>> = Mutex::new(Vec::new());
+    let _state = crate::testutil::global_state();
+    // Other tests may be running commands concurrently, so only record ours
+    const MARKER: &str = "pre-spawn-hook-marker";
+    set_pre_spawn_hook(|cmd| {
+        if cmd.get_args().any(|a| a == MARKER) {
+            SEEN.lock()
+                .unwrap()
+                .push(cmd.get_program().to_string_lossy().into_owned());
+        }
+    });
+    Command::new("true").arg(MARKER).run().unwrap();
+    crate::testutil::block_on(async {
+        tokio::process::Command::new("echo")
+            .arg(MARKER)
+            .run()
+            .await
+            .unwrap();
+    });
+    clear_pre_spawn_hook();
+    Command::new("true").arg(MARKER).run().unwrap();
+    assert_eq!(*SEEN.lock().unwrap(), ["true", "echo"]);
+}
+
+#[test]
+fn test_run_batch() {
+    use tokio::process::Command as AsyncCommand;
+    crate::testutil::block_on(async {
+        let commands = (0..4)
+            .map(|i| {
+                let mut cmd = AsyncCommand::new("/bin/sh");
+                cmd.args(["-c", "sleep 0.2; exit $0"])
+                    .arg(if i == 2 { "1" } else { "0" });
+                cmd
+            })
+            .collect();
+        let start = Instant::now();
+        let results = run_batch(commands, 2).await;
+        // With two at a time, this must have taken at least two rounds
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        assert_eq!(results.len(), 4);
+        for (i, r) in results.iter().enumerate() {
+            assert_eq!(r.is_err(), i == 2);
+        }
+    });
+}
+
+#[test]
+fn test_run_batch_parse_json() {
+    use tokio::process::Command as AsyncCommand;
+    crate::testutil::block_on(async {
+        let commands = (0..4)
+            .map(|i| {
+                let mut cmd = AsyncCommand::new("/bin/sh");
+                cmd.args([
+                    "-c",
+                    r#"test "$0" != 2 || { echo oops 1>&2; exit 1; }; echo "[$0]""#,
+                ])
+                .arg(i.to_string());
+                cmd
+            })
+            .collect();
+        let results = run_batch_parse_json::<Vec<u32>>(commands, 2).await;
+        assert_eq!(results.len(), 4);
+        for (i, r) in results.into_iter().enumerate() {
+            match r {
+                Ok(v) => assert_eq!(v, [i as u32]),
+                Err(e) => {
+                    assert_eq!(i, 2);
+                    assert_eq!(e.downcast_ref::<CommandError>().unwrap().stderr, "oops\n");
+                }
+            }
+        }
+    });
+}
@@ -18,6 +18,7 @@
 #![allow(clippy::needless_borrows_for_generic_args)]
 
 pub mod cli;
+mod cmdutils;
 pub(crate) mod deploy;
 pub(crate) mod generator;
 pub(crate) mod journal;
@@ -29,6 +30,8 @@ mod reboot;
 mod reexec;
 mod status;
 mod task;
+#[cfg(test)]
+mod testutil;
 mod utils;
 
 #[cfg(feature = "install")]
@@ -0,0 +1,104 @@
+//! Helpers for unit tests.
+
+use std::ffi::OsStr;
+use std::fmt::Write as _;
+use std::future::Future;
+use std::path::Path;
+use std::process::Command;
+use std::sync::{Mutex, MutexGuard};
+
+/// Assert that `cmd` would execute `program` with exactly the provided arguments,
+/// without executing it. On mismatch, the arguments are shown as a diff.
+#[track_caller]
+pub(crate) fn assert_command(cmd: &Command, program: &str, args: &[&str]) {
+    let actual_program = cmd.get_program().to_string_lossy();
+    let actual_args: Vec<_> = cmd.get_args().map(OsStr::to_string_lossy).collect();
+    if actual_program == program && actual_args.iter().eq(args.iter()) {
+        return;
+    }
+    let mut diff = String::new();
+    if actual_program != program {
+        writeln!(diff, "-program: {program}").unwrap();
+        writeln!(diff, "+program: {actual_program}").unwrap();
+    }
+    let actual: Vec<&str> = actual_args.iter().map(AsRef::as_ref).collect();
+    diff.push_str(&crate::cmdutils::diff_lines(args, &actual));
+    panic!("Command does not match (- expected, + actual):\n{diff}");
+}
+
+/// Assert that `key` is explicitly set to `value` in the environment of `cmd`,
+/// or if `value` is `None`, that it is explicitly removed.
+#[track_caller]
+pub(crate) fn assert_command_env(cmd: &Command, key: &str, value: Option<&str>) {
+    let Some((_, actual)) = cmd.get_envs().find(|(k, _)| *k == key) else {
+        panic!("Command does not set environment variable {key}");
+    };
+    assert_eq!(
+        actual.map(OsStr::to_string_lossy).as_deref(),
+        value,
+        "Environment variable {key}"
+    );
+}
+
+/// Assert that `cmd` would execute in the directory `dir`.
+#[track_caller]
+pub(crate) fn assert_command_dir(cmd: &Command, dir: &Path) {
+    assert_eq!(cmd.get_current_dir(), Some(dir), "Working directory");
+}
+
+/// Run `f` to completion on a new single-threaded runtime.
+pub(crate) fn block_on<F: Future>(f: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(f)
+}
+
+/// Held by each test which changes process-global configuration (such as the
+/// pre-spawn hook) via [`global_state`], so that such tests are serialized.
+#[derive(Debug)]
+pub(crate) struct GlobalState {
+    _lock: MutexGuard<'static, ()>,
+}
+
+/// Wait until no other test is changing process-global configuration, and return
+/// a guard which restores the defaults when dropped (even if the test panicked).
+pub(crate) fn global_state() -> GlobalState {
+    static LOCK: Mutex<()> = Mutex::new(());
+    GlobalState {
+        // The state is restored even if a test panicked while holding the lock
+        _lock: LOCK.lock().unwrap_or_else(|e| e.into_inner()),
+    }
+}
+
+impl Drop for GlobalState {
+    fn drop(&mut self) {
+        crate::cmdutils::set_capture_tempdir(None);
+        crate::cmdutils::clear_pre_spawn_hook();
+        crate::cmdutils::set_metrics_enabled(false);
+    }
+}
+
+#[test]
+fn test_assert_command() {
+    let mut cmd = Command::new("podman");
+    cmd.args(["pull", "quay.io/example"])
+        .env("FOO", "bar")
+        .env_remove("BAZ")
+        .current_dir("/sysroot");
+    assert_command(&cmd, "podman", &["pull", "quay.io/example"]);
+    assert_command_env(&cmd, "FOO", Some("bar"));
+    assert_command_env(&cmd, "BAZ", None);
+    assert_command_dir(&cmd, Path::new("/sysroot"));
+
+    let e = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        assert_command(&cmd, "podman", &["pull", "--quiet", "quay.io/example"])
+    }))
+    .err()
+    .unwrap();
+    assert_eq!(
+        e.downcast_ref::<String>().unwrap(),
+        "Command does not match (- expected, + actual):\n pull\n---quiet\n quay.io/example\n"
+    );
+}